@@ -1,14 +1,24 @@
 use log::error;
-use rms::message::Message;
+use rms::message::{address, Message};
 use rms::readmail::html2text;
 mod readmail_cmd;
 use readmail_cmd::source;
+use rms::message::mbox::Mbox;
 use rms::message::Mime;
-use std::io::BufRead;
+use std::io::{self, BufRead};
 
 fn main() {
-    let src: Box<dyn BufRead> = source();
-    let b_msg_rslt = src.split(3);
+    let (mut src, destination) = source();
+    // An mbox starts with a From line, anything else is messages separated by ETX bytes.
+    let is_mbox = src
+        .fill_buf()
+        .map(|buf| buf.starts_with(b"From "))
+        .unwrap_or(false);
+    let b_msg_rslt: Box<dyn Iterator<Item = io::Result<Vec<u8>>>> = if is_mbox {
+        Box::new(Mbox::new(src))
+    } else {
+        Box::new(src.split(3))
+    };
     for m in b_msg_rslt {
         match m {
             Ok(buf) => {
@@ -16,7 +26,7 @@ fn main() {
                 match message {
                     Ok(message) => {
                         println!("From: {}", message.from);
-                        println!("To: {}", message.recipients.join(", "));
+                        println!("To: {}", address::join(&message.to));
                         println!("Subject: {}", message.subject);
                         let body = message.get_body(None);
                         match body.mime {
@@ -24,6 +34,14 @@ fn main() {
                             Mime::Html => println!("\n\n{}", html2text(&body.value)),
                             _ => println!("Unknown mime type"),
                         }
+                        if let Some(dir) = destination.as_ref() {
+                            match message.save_attachments(None, dir) {
+                                Ok(saved) => saved
+                                    .iter()
+                                    .for_each(|p| println!("Saved {}", p.display())),
+                                Err(e) => error!("{}", e.message),
+                            }
+                        }
                     }
                     Err(_e) => error!("Failed to make sense of the message"),
                 }
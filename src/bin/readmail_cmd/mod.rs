@@ -10,7 +10,7 @@ fn expand_path(input_str: &str) -> PathBuf {
     PathBuf::from(expanded)
 }
 
-pub fn source() -> Box<dyn BufRead> {
+pub fn source() -> (Box<dyn BufRead>, Option<PathBuf>) {
     let matches = App::new("Read Mail")
         .version("0.0.1")
         .author("Lewis Diamond <rms@lewisdiamond.com>")
@@ -23,11 +23,13 @@ pub fn source() -> Box<dyn BufRead> {
         )])
         .get_matches();
 
-    match matches.value_of("input") {
+    let destination = matches.value_of("destination").map(expand_path);
+    let input: Box<dyn BufRead> = match matches.value_of("input") {
         None => Box::new(BufReader::new(io::stdin())),
         Some(filename) => {
             let expand = expand_path(filename);
             Box::new(BufReader::new(fs::File::open(expand).unwrap()))
         }
-    }
+    };
+    (input, destination)
 }
@@ -1,41 +1,241 @@
 use log::{error, info, trace};
-use rms::cmd::{opts, Command};
-use rms::readmail::display::DisplayAs;
+use rms::cmd::{expand_path, opts, Command, OutputOpts, SpamCommand};
+use rms::config::{self, Config};
+use rms::message::json::JsonMessage;
+use rms::message::maildir::{self, ExportMode};
+use rms::message::mbox::{self, Mbox};
+use rms::message::Message;
+use rms::readmail;
+use rms::readmail::display::{DisplayAs, OutputType};
+use rms::readmail::template::Template;
+use rms::stores::address_book;
 use rms::stores::kv::Kv;
-use rms::stores::message_store::MessageStore;
+use rms::stores::message_store::{InsertError, MailFileAction, MessageStore};
+use rms::stores::query::{self, date, Expr};
+use rms::stores::report::IndexReport;
+use rms::stores::search::{Searcher, SortOrder};
+use rms::stores::MessageStoreError;
 use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process;
+use tempdir::TempDir;
+
+// sysexits.h codes, understood by MTAs and fetchmail when rms is used as an MDA.
+const EX_DATAERR: i32 = 65;
+const EX_CANTCREAT: i32 = 73;
+const EX_IOERR: i32 = 74;
+const EX_TEMPFAIL: i32 = 75;
+const EX_CONFIG: i32 = 78;
+
+enum Printer {
+    Output(OutputType),
+    Template(Template),
+}
+
+/// Exit code of `rms insert` when the store fails: broken rules or spam model won't fix
+/// themselves, anything else (a locked or unreadable index) is worth a retry.
+fn store_exit_code(e: &MessageStoreError) -> i32 {
+    match e {
+        MessageStoreError::InvalidQuery(_) => EX_CONFIG,
+        MessageStoreError::CouldNotLoadSpamModel(_) => EX_DATAERR,
+        _ => EX_TEMPFAIL,
+    }
+}
+
+/// Picks the template or output type requested on the command line, falling back to the
+/// config file.
+fn printer(opts: OutputOpts, config: &Config) -> Printer {
+    match opts.format {
+        Some(format) => {
+            let template = config.templates.get(&format).unwrap_or(&format);
+            match Template::parse(template) {
+                Ok(template) => Printer::Template(template),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => Printer::Output(opts.output.or(config.output).unwrap_or(OutputType::Short)),
+    }
+}
+
+fn print_message(msg: &Message, printer: &Printer) {
+    match printer {
+        Printer::Template(template) => println!("{}", template.render(msg)),
+        Printer::Output(output) => println!("{}", msg.display(output)),
+    }
+}
+
+/// Prints messages with the given printer, JSON output being a single array.
+fn print_messages(msgs: &[Message], printer: &Printer) {
+    match printer {
+        Printer::Output(OutputType::Json) => {
+            let json = msgs.iter().map(JsonMessage::new).collect::<Vec<JsonMessage>>();
+            println!("{}", serde_json::to_string_pretty(&json).unwrap_or_default());
+        }
+        _ => msgs.iter().for_each(|m| print_message(m, printer)),
+    }
+}
+
+/// Opens `path` with the html viewer of the config, `$BROWSER` or xdg-open. `%s` in the
+/// command stands for the path, which is appended otherwise.
+fn open_html(path: &Path, config: &Config) -> io::Result<()> {
+    let command = config
+        .html_viewer
+        .clone()
+        .or_else(|| env::var("BROWSER").ok())
+        .and_then(|c| c.split(':').next().map(str::to_string))
+        .filter(|c| !c.trim().is_empty())
+        .unwrap_or_else(|| "xdg-open".to_string());
+    let path = path.to_string_lossy();
+    let mut args = command
+        .split_whitespace()
+        .map(|arg| arg.replace("%s", &path))
+        .collect::<Vec<String>>();
+    if !command.contains("%s") {
+        args.push(path.into_owned());
+    }
+    let status = process::Command::new(&args[0]).args(&args[1..]).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} exited with {}", args[0], status),
+        ))
+    }
+}
+
+/// Writes `messages` as an mbox to `output`, or stdout.
+fn export_mbox(messages: &[Message], output: Option<&Path>) -> Result<(), String> {
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => fs::File::create(path)
+            .map(|file| Box::new(io::BufWriter::new(file)) as Box<dyn Write>)
+            .map_err(|e| format!("Could not create {}: {}", path.display(), e))?,
+        None => Box::new(io::BufWriter::new(io::stdout())),
+    };
+    messages
+        .iter()
+        .try_for_each(|m| mbox::write_message(&mut out, &m.original, &m.from.email, m.date))
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("Could not write the mbox: {}", e))
+}
+
+/// Puts `messages` in a new maildir at `root`.
+fn export_maildir(messages: &[Message], root: &Path, mode: ExportMode) -> Result<(), String> {
+    maildir::create_maildir(root)
+        .map_err(|e| format!("Could not create a maildir at {}: {}", root.display(), e))?;
+    for m in messages {
+        maildir::export_message(m, root, mode)
+            .map_err(|e| format!("Could not export {}: {}", m.id, e))?;
+    }
+    println!("Exported {} messages to {}", messages.len(), root.display());
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
     let opt = opts();
-    trace!("Using config file at {:?}", opt.config); //, index.maildir_path);
-    let index_dir_path = opt.index_dir_path;
-    let message_store = MessageStore::new(index_dir_path);           
+    // Only rms insert, run by MTAs, reports setup failures with sysexits codes.
+    let inserting = matches!(opt.cmd, Command::Insert { .. });
+    let exit_code = |code: i32| if inserting { code } else { 1 };
+    trace!("Using config file at {:?}", config::resolve(&opt.config));
+    let config = match Config::load(&opt.config) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(exit_code(EX_CONFIG));
+        }
+    };
+    let index_dir_path = match opt.index_dir_path.or_else(|| config.index_dir.clone()) {
+        Some(path) => path,
+        None => {
+            error!("No index directory, set index_dir in the config file or use --index-dir-path");
+            process::exit(exit_code(EX_CONFIG));
+        }
+    };
+    let message_store = MessageStore::new(index_dir_path).and_then(|store| store.with_config(&config));
+    let page_size = &config.page_size;
+    if let Some(width) = config.html_width {
+        readmail::set_html_width(width);
+    }
 
     match opt.cmd {
         Command::Index {
             maildir_path,
             full,
             debug: _,
+            watch,
+            report,
+            strict,
         } => {
+            let maildir_path = if maildir_path.is_empty() {
+                config.maildirs.values().cloned().collect()
+            } else {
+                maildir_path
+                    .into_iter()
+                    .map(|m| {
+                        m.to_str()
+                            .and_then(|name| config.maildirs.get(name))
+                            .cloned()
+                            .unwrap_or(m)
+                    })
+                    .collect::<Vec<_>>()
+            };
+            if maildir_path.is_empty() {
+                error!("No maildir to index, use --maildir-path or add maildirs to the config file");
+                process::exit(1);
+            }
             info!("Indexing {:?}", maildir_path);
             if full {
                 info!("Full indexing selected.");
             }
             match message_store {
                 Ok(mut store) => {
-                    for m in maildir_path {
+                    let mut index_report = IndexReport::default();
+                    let mut failed = false;
+                    for m in &maildir_path {
                         println!("Adding maildir at {}", m.to_str().unwrap());
                         match store.add_maildir(m.clone(), full).await {
-                            Err(e) => error!(
-                                "Failed to add mails from {}, details: {}",
-                                m.to_str().unwrap(),
-                                e
-                            ),
-                            Ok(_) => println!("Successfully added {}", m.to_str().unwrap()),
+                            Err(e) => {
+                                failed = true;
+                                error!(
+                                    "Failed to add mails from {}, details: {}",
+                                    m.to_str().unwrap(),
+                                    e
+                                )
+                            }
+                            Ok(r) => {
+                                println!("Successfully added {}", m.to_str().unwrap());
+                                index_report.merge(r);
+                            }
                         };
                     }
+                    println!("{}", index_report);
+                    if let Some(report_path) = report {
+                        let written = serde_json::to_string_pretty(&index_report)
+                            .map_err(|e| e.to_string())
+                            .and_then(|json| {
+                                fs::write(&report_path, json).map_err(|e| e.to_string())
+                            });
+                        if let Err(e) = written {
+                            error!("Could not write report to {}: {}", report_path.display(), e);
+                        }
+                    }
+                    if strict && (failed || index_report.has_failures()) {
+                        process::exit(1);
+                    }
+                    if watch {
+                        if let Err(e) = store.watch_maildirs(&maildir_path) {
+                            error!("{}", e);
+                        }
+                    }
                 }
                 Err(e) => {
                     error!("{}", e);
@@ -51,17 +251,145 @@ async fn main() {
             //let mut indexer = indexer_builder.build();
             //message_store.index_mails(full);
         }
+        Command::Import { mbox, tag } => match message_store {
+            Ok(mut store) => {
+                let file = match fs::File::open(&mbox) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        error!("Could not open {}: {}", mbox.display(), e);
+                        process::exit(1);
+                    }
+                };
+                let messages = Mbox::new(io::BufReader::new(file));
+                let tags = tag.into_iter().collect::<HashSet<String>>();
+                match store.import_messages(messages, &mbox, &tags) {
+                    Ok(report) => {
+                        println!("{}", report);
+                        if report.has_failures() {
+                            process::exit(1);
+                        }
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+            Err(e) => error!("{}", e),
+        },
+        Command::Insert {
+            maildir,
+            folder,
+            tags,
+        } => {
+            let root = match maildir {
+                Some(name) => config
+                    .maildirs
+                    .get(&name)
+                    .cloned()
+                    .unwrap_or_else(|| expand_path(name.as_ref())),
+                None if config.maildirs.len() == 1 => {
+                    config.maildirs.values().next().cloned().unwrap_or_default()
+                }
+                None => {
+                    error!("Choose a maildir to deliver to with --maildir");
+                    process::exit(EX_CONFIG);
+                }
+            };
+            let mut data = vec![];
+            if let Err(e) = io::stdin().read_to_end(&mut data) {
+                error!("Could not read the message: {}", e);
+                process::exit(EX_IOERR);
+            }
+            let mut store = match message_store {
+                Ok(store) => store,
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(store_exit_code(&e));
+                }
+            };
+            let tags = tags.into_iter().collect::<HashSet<String>>();
+            match store.insert_message(data, &root, &folder, &tags) {
+                Ok(Some(msg)) => println!("{}", msg.id),
+                Ok(None) => info!("The message is already indexed"),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(match e {
+                        InsertError::NotAMessage(_) => EX_DATAERR,
+                        InsertError::CouldNotDeliver(_) => EX_CANTCREAT,
+                        InsertError::Store(e) => store_exit_code(&e),
+                    });
+                }
+            }
+        }
+        Command::Export {
+            mbox: _,
+            maildir,
+            mode,
+            query,
+            thread,
+            num,
+            output,
+        } => match message_store {
+            Ok(store) => {
+                let messages = match query {
+                    // Without --num every match is exported, not a page of them.
+                    Some(query) => num
+                        .map_or_else(|| store.searcher.count(&query), Ok)
+                        .and_then(|num| store.searcher.query(&query, num, SortOrder::Ascending))
+                        .map_err(|e| e.to_string()),
+                    None => {
+                        let id = thread.expect("structopt requires a query unless --thread");
+                        match store.get_message(&id) {
+                            Ok(Some(msg)) => store
+                                .get_thread(&msg)
+                                .map(|thread| thread.into_iter().map(|(_, m)| m).collect())
+                                .map_err(|e| e.to_string()),
+                            Ok(None) => Err(format!("Message {} not found", id)),
+                            Err(e) => Err(e.to_string()),
+                        }
+                    }
+                };
+                let messages: Vec<Message> = match messages {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        error!("{}", e);
+                        process::exit(1);
+                    }
+                };
+                let written = match maildir {
+                    Some(root) => export_maildir(&messages, &root, mode.unwrap_or(ExportMode::Copy)),
+                    None => export_mbox(&messages, output.as_deref()),
+                };
+                if let Err(e) = written {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+            Err(e) => error!("{}", e),
+        },
         Command::Search {
             term,
             output,
             num,
             advanced,
+            ascending,
         } => {
             match message_store {
                 Ok(store) => {
-                    let results = store.searcher.fuzzy(&term, num);
-                    for r in results {
-                        println!("{}", r.display(&output));
+                    let results = if advanced {
+                        let order = if ascending {
+                            SortOrder::Ascending
+                        } else {
+                            SortOrder::Descending
+                        };
+                        store.searcher.query(&term, num.unwrap_or(page_size.search), order)
+                    } else {
+                        Ok(store.searcher.fuzzy(&term, num.unwrap_or(page_size.search)))
+                    };
+                    match results {
+                        Ok(results) => print_messages(&results, &printer(output, &config)),
+                        Err(e) => error!("{}", e),
                     }
                     //match output {
                     //                       OutputType::Short => {
@@ -97,12 +425,34 @@ async fn main() {
                 Err(e) => error!("{}", e),
             }
         }
-        Command::Date { term: _term } => {
-            //let mut message_store = MessageStoreBuilder::new().build(); //maildir_path[0].clone(), index_dir_path);
-            //let searcher = Searcher::new(index_dir_path);
-            //let results = searcher.by_date();
-            //println!("{:?}", results);
-        }
+        Command::Date {
+            term,
+            query,
+            output,
+            num,
+            ascending,
+        } => match message_store {
+            Ok(store) => {
+                let order = if ascending {
+                    SortOrder::Ascending
+                } else {
+                    SortOrder::Descending
+                };
+                let expr = date::parse_range(&term)
+                    .map(|(start, end)| Expr::Date { start, end })
+                    .map_err(rms::stores::MessageStoreError::InvalidQuery)
+                    .and_then(|by_date| match query {
+                        Some(q) => query::parse(&q).map(|text| Expr::And(vec![by_date, text])),
+                        None => Ok(by_date),
+                    });
+                let num = num.unwrap_or(page_size.search);
+                match expr.and_then(|expr| store.searcher.search_expr(&expr, num, order)) {
+                    Ok(results) => print_messages(&results, &printer(output, &config)),
+                    Err(e) => error!("{}", e),
+                }
+            }
+            Err(e) => error!("{}", e),
+        },
 
         Command::Test {} => {
             //let message_store = MessageStoreBuilder::new().build(); //maildir_path[0].clone(), index_dir_path);
@@ -110,13 +460,36 @@ async fn main() {
             //println!("Num cpus: {}", num_cpu);
         }
 
-        Command::Get { id, output } => {
+        Command::Get {
+            id,
+            output,
+            parts,
+            part,
+        } => {
 
             match message_store {
                 Ok(store) => {
-                    let result = store.kv.get_message(&id);
+                    let result = store.get_message(&id);
                     match result {
-                        Ok(Some(good_msg)) => println!("{}", good_msg.display(&output)),
+                        Ok(Some(good_msg)) if parts => match good_msg.mime_tree() {
+                            Ok(tree) => println!("{}", tree.outline()),
+                            Err(e) => error!("{}", e.message),
+                        },
+                        Ok(Some(good_msg)) if part.is_some() => {
+                            let number = part.unwrap_or_default();
+                            match good_msg.part_content(&number) {
+                                Ok(content) => {
+                                    if let Err(e) = io::stdout().write_all(&content) {
+                                        error!("Unable to write part {}: {}", number, e);
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("{}", e.message);
+                                    process::exit(1);
+                                }
+                            }
+                        }
+                        Ok(Some(good_msg)) => print_message(&good_msg, &printer(output, &config)),
                         Ok(None) => error!("Message not found"),
                         Err(e) => error!("ERROR {}", e),
                     }
@@ -124,6 +497,61 @@ async fn main() {
                 Err(e) => error!("Store isn't right... {}", e),
             }
         }
+        Command::View { id, html, remote } => match message_store {
+            Ok(store) => match store.get_message(&id) {
+                Ok(Some(msg)) if html => {
+                    // A fresh directory only we can read, kept once rms exits since viewers
+                    // such as xdg-open return before the page is loaded.
+                    let opened = TempDir::new(&format!("rms-{}", msg.short_id()))
+                        .and_then(|dir| {
+                            fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))
+                                .map(|_| dir.into_path())
+                        })
+                        .map_err(|e| format!("Unable to create a temporary directory: {}", e))
+                        .and_then(|dir| msg.write_html(&dir, remote).map_err(|e| e.message))
+                        .and_then(|path| open_html(&path, &config).map_err(|e| e.to_string()));
+                    if let Err(e) = opened {
+                        error!("{}", e);
+                        process::exit(1);
+                    }
+                }
+                Ok(Some(msg)) => println!("{}", msg.display(&OutputType::Full)),
+                Ok(None) => error!("Message not found"),
+                Err(e) => error!("{}", e),
+            },
+            Err(e) => error!("{}", e),
+        },
+        Command::Thread { id, output } => match message_store {
+            Ok(store) => match store.get_message(&id) {
+                Ok(Some(msg)) => match store.get_thread(&msg) {
+                    Ok(thread) => match printer(output, &config) {
+                        Printer::Output(OutputType::Json) => {
+                            let json = thread
+                                .iter()
+                                .map(|(depth, m)| JsonMessage::new(m).with_depth(*depth))
+                                .collect::<Vec<JsonMessage>>();
+                            println!("{}", serde_json::to_string_pretty(&json).unwrap_or_default());
+                        }
+                        Printer::Output(OutputType::Jsonl) => {
+                            for (depth, m) in thread.iter() {
+                                let json = JsonMessage::new(m).with_depth(*depth);
+                                println!("{}", serde_json::to_string(&json).unwrap_or_default());
+                            }
+                        }
+                        printer => {
+                            for (depth, m) in thread {
+                                print!("{}", "  ".repeat(depth));
+                                print_message(&m, &printer);
+                            }
+                        }
+                    },
+                    Err(e) => error!("{}", e),
+                },
+                Ok(None) => error!("Message not found"),
+                Err(e) => error!("{}", e),
+            },
+            Err(e) => error!("{}", e),
+        },
         Command::Interactive {} => {
             //terminal::start(index_dir_path).unwrap();
         }
@@ -134,13 +562,9 @@ async fn main() {
         } => {
             match message_store {
                 Ok(store) => {
-                    let page = store.kv.get_messages(skip, _num);
+                    let page = store.kv.get_messages(skip, _num.unwrap_or(page_size.latest));
                     match page {
-                        Ok(msgs) => {
-                            for m in msgs {
-                                println!("{}", m.display(&output));
-                            }
-                        }
+                        Ok(msgs) => print_messages(&msgs, &printer(output, &config)),
                         Err(e) => println!("Could not read messages, {}", e),
                     }
                 }
@@ -150,15 +574,148 @@ async fn main() {
         Command::Tag { id, tags } => {
             match message_store {
                 Ok(mut store) => {
-                    if let Err(e) =
-                        store.kv.tag_message_id(&id, tags.into_iter().collect::<HashSet<String>>())
-                    {
-                        error!("{}", e)
+                    match store.tag_message_id(&id, tags.into_iter().collect::<HashSet<String>>()) {
+                        Ok(msg) => {
+                            let mut tags = msg.tags.into_iter().collect::<Vec<String>>();
+                            tags.sort();
+                            println!("{}", tags.join(" "));
+                        }
+                        Err(e) => error!("{}", e),
                     }
                 }
                 Err(e) => error!("{}", e),
             }
         }
+        Command::Tags {} => match message_store {
+            Ok(store) => match store.kv.count_tags() {
+                Ok(counts) => {
+                    let mut counts = counts.into_iter().collect::<Vec<(String, usize)>>();
+                    counts.sort();
+                    for (tag, count) in counts {
+                        println!("{}\t{}", tag, count);
+                    }
+                }
+                Err(e) => error!("{}", e),
+            },
+            Err(e) => error!("{}", e),
+        },
+        Command::Spam { cmd } => match message_store {
+            Ok(mut store) => match cmd {
+                SpamCommand::Train {} => match store.train_spam() {
+                    Ok((spam, ham)) => println!("Learned {} spam and {} ham messages", spam, ham),
+                    Err(e) => {
+                        error!("{}", e);
+                        process::exit(1);
+                    }
+                },
+                SpamCommand::Score { id } => match store.get_message(&id) {
+                    Ok(Some(msg)) => match store.spam_score(&msg) {
+                        Some(score) => println!("{:.3}", score),
+                        None => {
+                            error!("Tag messages spam and ham, then run rms spam train");
+                            process::exit(1);
+                        }
+                    },
+                    Ok(None) => error!("Message not found"),
+                    Err(e) => error!("{}", e),
+                },
+            },
+            Err(e) => error!("{}", e),
+        },
+        Command::Retag { query } => match message_store {
+            Ok(mut store) => match store.retag(query.as_deref()) {
+                Ok(changed) => println!("Retagged {} messages", changed),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            },
+            Err(e) => error!("{}", e),
+        },
+        Command::Address {
+            query,
+            fuzzy,
+            output,
+            num,
+            rebuild,
+        } => match message_store {
+            Ok(mut store) => {
+                if rebuild {
+                    match store.kv.rebuild_address_book() {
+                        Ok(count) => info!("Recorded {} correspondents", count),
+                        Err(e) => {
+                            error!("{}", e);
+                            process::exit(1);
+                        }
+                    }
+                }
+                match store.kv.get_correspondents() {
+                    Ok(entries) => {
+                        let mut found = address_book::lookup(&entries, &query, fuzzy);
+                        if let Some(num) = num {
+                            found.truncate(num);
+                        }
+                        println!("{}", address_book::format(&found, output));
+                    }
+                    Err(e) => error!("{}", e),
+                }
+            }
+            Err(e) => error!("{}", e),
+        },
+        Command::Attachments { id } => match message_store {
+            Ok(store) => match store.get_message(&id) {
+                Ok(Some(msg)) => {
+                    for a in msg.attachments {
+                        println!(
+                            "{}\t{}\t{}\t{}",
+                            a.filename,
+                            a.content_type,
+                            a.size,
+                            a.content_id.unwrap_or_default()
+                        );
+                    }
+                }
+                Ok(None) => error!("Message not found"),
+                Err(e) => error!("{}", e),
+            },
+            Err(e) => error!("{}", e),
+        },
+        Command::SaveAttachment { id, name, output } => match message_store {
+            Ok(store) => match store.get_message(&id) {
+                Ok(Some(msg)) => match msg.save_attachments(name.as_deref(), &output) {
+                    Ok(saved) if saved.is_empty() => error!("No matching attachment"),
+                    Ok(saved) => {
+                        for path in saved {
+                            println!("{}", path.display());
+                        }
+                    }
+                    Err(e) => error!("{}", e.message),
+                },
+                Ok(None) => error!("Message not found"),
+                Err(e) => error!("{}", e),
+            },
+            Err(e) => error!("{}", e),
+        },
+        Command::Delete {
+            id,
+            keep_file,
+            trash,
+        } => match message_store {
+            Ok(mut store) => {
+                let action = if keep_file {
+                    MailFileAction::Keep
+                } else if trash {
+                    MailFileAction::Trash
+                } else {
+                    MailFileAction::Remove
+                };
+                match store.delete_message_by_id(&id, action) {
+                    Ok(msg) => println!("Deleted {}", msg.id),
+                    Err(e) => error!("{}", e),
+                }
+            }
+            Err(e) => error!("{}", e),
+        },
     }
 
     //create_index();
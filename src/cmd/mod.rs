@@ -1,7 +1,9 @@
 use std::ffi::OsStr;
 use std::path::PathBuf;
 use structopt::StructOpt;
+use crate::message::maildir::ExportMode;
 use crate::readmail::display::OutputType;
+use crate::stores::address_book::AddressFormat;
 
 pub fn expand_path(input: &OsStr) -> PathBuf {
     let input_str = input
@@ -16,6 +18,18 @@ pub fn expand_path(input: &OsStr) -> PathBuf {
 
 
 
+#[derive(Debug, StructOpt)]
+pub struct OutputOpts {
+    /// summary, short, full, raw, html, json or jsonl
+    #[structopt(short, long)]
+    pub output: Option<OutputType>,
+
+    /// A template such as '{date:%Y-%m-%d} {from.name} {subject} [{tags}]' or the name of a
+    /// template of the config file. Takes precedence over --output
+    #[structopt(long)]
+    pub format: Option<String>,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "Rust Mail System",
@@ -25,22 +39,23 @@ pub fn expand_path(input: &OsStr) -> PathBuf {
     rename_all = "kebab-case"
 )]
 pub struct Opt {
+    /// Colon separated list of config files, the first one that exists is used
     #[structopt(
-        parse(from_os_str = expand_path),
         short,
         long,
         env = "RMS_CONFIG_PATH",
         default_value = "~/.config/rms/config:~/.config/rms/rmsrc:~/.rmsrc:/etc/rms/config:/etc/rms/rmsrc"
     )]
-    pub config: PathBuf,
+    pub config: String,
 
+    /// Overrides the index_dir of the config file
     #[structopt(
         parse(from_os_str = expand_path),
         short,
         long,
         env = "RMS_INDEX_DIR_PATH"
     )]
-    pub index_dir_path: PathBuf,
+    pub index_dir_path: Option<PathBuf>,
 
     #[structopt(subcommand)]
     pub cmd: Command,
@@ -50,11 +65,12 @@ pub struct Opt {
 pub enum Command {
     #[structopt(name = "index", rename_all = "kebab-case")]
     Index {
+        /// Maildirs to index, along with their Maildir++ subfolders. Accepts the names of the
+        /// maildirs of the config file, which are all indexed when none is given
         #[structopt(
             parse(from_os_str = expand_path),
             short,
             long,
-            required = true,
             env = "RMS_MAILDIR_PATH"
         )]
         maildir_path: Vec<PathBuf>,
@@ -63,27 +79,155 @@ pub enum Command {
         full: bool,
         #[structopt(short, long)]
         debug: bool,
+
+        /// Keep running and index new messages as they arrive
+        #[structopt(short, long)]
+        watch: bool,
+
+        /// Write the indexing report as JSON to this file
+        #[structopt(parse(from_os_str = expand_path), short, long)]
+        report: Option<PathBuf>,
+
+        /// Exit with a non-zero status if any message failed to index
+        #[structopt(short, long)]
+        strict: bool,
+    },
+
+    /// Index the messages of an mbox, mboxrd and mboxcl2 included
+    #[structopt(name = "import", rename_all = "kebab-case")]
+    Import {
+        #[structopt(parse(from_os_str = expand_path), long)]
+        mbox: PathBuf,
+
+        /// Tags to add (+tag or tag) or remove (-tag) on every imported message
+        #[structopt(short, long, allow_hyphen_values = true)]
+        tag: Vec<String>,
+    },
+
+    /// Deliver a message read from stdin to a maildir and index it, for use as an MDA. Exits
+    /// with sysexits codes: 65 for invalid input, 73 when the maildir can't be written, 75
+    /// when the message could not be indexed and should be retried and 78 when the config file
+    /// is invalid or names no maildir
+    #[structopt(name = "insert", rename_all = "kebab-case")]
+    Insert {
+        /// Name of a maildir of the config file, or a path. Defaults to the only configured
+        /// maildir
+        #[structopt(short, long)]
+        maildir: Option<String>,
+
+        /// Maildir++ folder to deliver to, such as Lists.rust
+        #[structopt(short, long, default_value = "INBOX")]
+        folder: String,
+
+        /// Tags to add (+tag or tag) or remove (-tag), e.g. -unread to deliver as read
+        #[structopt(allow_hyphen_values = true)]
+        tags: Vec<String>,
+    },
+
+    /// Write the messages matching a search query, or a thread, as an mboxrd or to a new
+    /// maildir
+    #[structopt(name = "export", rename_all = "kebab-case")]
+    Export {
+        #[structopt(long, required_unless = "maildir", conflicts_with = "maildir")]
+        mbox: bool,
+
+        /// Create a maildir here holding the messages, flagged after their tags
+        #[structopt(parse(from_os_str = expand_path), long)]
+        maildir: Option<PathBuf>,
+
+        /// copy, hardlink or symlink: how messages whose file is known end up in the maildir
+        #[structopt(long, requires = "maildir")]
+        mode: Option<ExportMode>,
+
+        /// An advanced search query
+        #[structopt(required_unless = "thread")]
+        query: Option<String>,
+
+        /// Export the thread of this message instead
+        #[structopt(long, conflicts_with = "query")]
+        thread: Option<String>,
+
+        /// Export at most this many of the matches, all of them by default
+        #[structopt(short, long)]
+        num: Option<usize>,
+
+        /// Write the mbox to this file instead of stdout
+        #[structopt(parse(from_os_str = expand_path), short, long, conflicts_with = "maildir")]
+        output: Option<PathBuf>,
     },
     #[structopt(name = "search", rename_all = "kebab-case")]
     Search {
         term: String,
 
-        #[structopt(short, long, default_value = "short")]
-        output: OutputType,
+        #[structopt(flatten)]
+        output: OutputOpts,
 
-        #[structopt(short, long, default_value = "100")]
-        num: usize,
+        #[structopt(short, long)]
+        num: Option<usize>,
 
         #[structopt(short, long)]
         advanced: bool,
+
+        /// Oldest messages first (advanced search only)
+        #[structopt(long)]
+        ascending: bool,
     },
     #[structopt(name = "date", rename_all = "kebab-case")]
-    Date { term: i64 },
+    Date {
+        /// A date or range: 2024-03-01..2024-03-31, yesterday, "last week", <30d, >1y
+        term: String,
+
+        /// Only return messages also matching this search query
+        #[structopt(short, long)]
+        query: Option<String>,
+
+        #[structopt(flatten)]
+        output: OutputOpts,
+
+        #[structopt(short, long)]
+        num: Option<usize>,
+
+        /// Oldest messages first
+        #[structopt(short, long)]
+        ascending: bool,
+    },
 
     #[structopt(rename_all = "kebab-case")]
     Get {
-        #[structopt(short, long, default_value = "short")]
-        output: OutputType,
+        #[structopt(flatten)]
+        output: OutputOpts,
+
+        /// Show the MIME structure of the message along with part numbers
+        #[structopt(long)]
+        parts: bool,
+
+        /// Output the content of the part with this number, such as 1.2
+        #[structopt(long, conflicts_with = "parts")]
+        part: Option<String>,
+
+        id: String,
+    },
+
+    /// Show a message, with --html in a browser
+    #[structopt(name = "view", rename_all = "kebab-case")]
+    View {
+        id: String,
+
+        /// Open a sanitized copy of the html part with html_viewer from the config, $BROWSER
+        /// or xdg-open
+        #[structopt(long)]
+        html: bool,
+
+        /// Let the browser load remote images and styles, which tells the sender the message
+        /// was read
+        #[structopt(long, requires = "html")]
+        remote: bool,
+    },
+
+    #[structopt(rename_all = "kebab-case")]
+    Thread {
+        #[structopt(flatten)]
+        output: OutputOpts,
 
         id: String,
     },
@@ -91,15 +235,87 @@ pub enum Command {
     #[structopt(rename_all = "kebab-case")]
     Latest {
         #[structopt(short, long)]
-        num: usize,
+        num: Option<usize>,
         #[structopt(short, long, default_value = "0")]
         skip: usize,
-        #[structopt(short, long, default_value = "short")]
-        output: OutputType,
+        #[structopt(flatten)]
+        output: OutputOpts,
     },
 
     #[structopt(name = "tag")]
-    Tag { id: String, tags: Vec<String> },
+    Tag {
+        id: String,
+
+        /// Tags to add (+tag or tag) or remove (-tag). The unread, replied, flagged, trashed,
+        /// draft and passed tags are written back to the maildir flags of the file
+        #[structopt(allow_hyphen_values = true)]
+        tags: Vec<String>,
+    },
+
+    #[structopt(name = "tags")]
+    Tags {},
+
+    /// Train the local spam filter, or see how it scores a message
+    #[structopt(name = "spam", rename_all = "kebab-case")]
+    Spam {
+        #[structopt(subcommand)]
+        cmd: SpamCommand,
+    },
+
+    /// Apply the tagging rules of the config file again, to every message or to those matching
+    /// an advanced search query
+    #[structopt(name = "retag", rename_all = "kebab-case")]
+    Retag { query: Option<String> },
+
+    #[structopt(name = "attachments", rename_all = "kebab-case")]
+    Attachments { id: String },
+
+    #[structopt(name = "save-attachment", rename_all = "kebab-case")]
+    SaveAttachment {
+        id: String,
+
+        /// Only save the attachment with this file name
+        name: Option<String>,
+
+        #[structopt(parse(from_os_str = expand_path), short, long, default_value = ".")]
+        output: PathBuf,
+    },
+
+    #[structopt(name = "delete", rename_all = "kebab-case")]
+    Delete {
+        id: String,
+
+        /// Only remove the message from the index, leave the maildir file alone
+        #[structopt(short, long)]
+        keep_file: bool,
+
+        /// Flag the maildir file as trashed (T) instead of removing it
+        #[structopt(short, long, conflicts_with = "keep-file")]
+        trash: bool,
+    },
+
+    /// Look up correspondents of indexed messages, usable as mutt's query_command
+    #[structopt(name = "address", rename_all = "kebab-case")]
+    Address {
+        /// Start of an email address or name, empty to list everyone
+        #[structopt(default_value = "")]
+        query: String,
+
+        /// Also match names and email users containing the query's characters in order
+        #[structopt(short, long)]
+        fuzzy: bool,
+
+        /// mutt or json
+        #[structopt(short, long, default_value = "mutt")]
+        output: AddressFormat,
+
+        #[structopt(short, long)]
+        num: Option<usize>,
+
+        /// Recount the address book from every indexed message first
+        #[structopt(long)]
+        rebuild: bool,
+    },
 
     #[structopt(name = "test", rename_all = "kebab-case")]
     Test {},
@@ -108,6 +324,18 @@ pub enum Command {
     Interactive {},
 }
 
+#[derive(Debug, StructOpt)]
+pub enum SpamCommand {
+    /// Learn from every message tagged spam or ham. New messages scoring above the threshold
+    /// of the config file are then tagged spam as they are indexed
+    #[structopt(name = "train")]
+    Train {},
+
+    /// Print the spam score of a message, from 0 to 1
+    #[structopt(name = "score")]
+    Score { id: String },
+}
+
 pub fn opts() -> Opt {
     Opt::from_args()
 }
@@ -0,0 +1,187 @@
+use crate::readmail::display::OutputType;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    CouldNotRead(PathBuf, io::Error),
+    CouldNotParse(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::CouldNotRead(path, e) => {
+                write!(f, "Could not read config file {}: {}", path.display(), e)
+            }
+            ConfigError::CouldNotParse(path, e) => {
+                write!(f, "Invalid config file {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PageSizes {
+    pub search: usize,
+    pub latest: usize,
+}
+
+impl Default for PageSizes {
+    fn default() -> PageSizes {
+        PageSizes {
+            search: 100,
+            latest: 20,
+        }
+    }
+}
+
+/// Limits of the tantivy index writer. Unset values are computed from the number of messages
+/// to index and the available memory.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WriterConfig {
+    pub threads: Option<usize>,
+    pub memory_per_thread_mb: Option<usize>,
+}
+
+/// The spam filter, trained with `rms spam train`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SpamConfig {
+    /// Score from 0 to 1 from which new messages are tagged as spam.
+    pub threshold: f64,
+}
+
+impl Default for SpamConfig {
+    fn default() -> SpamConfig {
+        SpamConfig { threshold: 0.9 }
+    }
+}
+
+/// Tags added (`tag`, `+tag`) or removed (`-tag`) from messages matching `query`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TagRule {
+    pub query: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub index_dir: Option<PathBuf>,
+    /// Maildirs by name, the name is what `folder:` searches match.
+    pub maildirs: BTreeMap<String, PathBuf>,
+    pub output: Option<OutputType>,
+    /// Output templates by name, usable with `--format <name>`.
+    pub templates: HashMap<String, String>,
+    pub page_size: PageSizes,
+    /// Width html bodies are wrapped to when shown as text.
+    pub html_width: Option<usize>,
+    /// Command opening html messages, `%s` standing for the file. Defaults to `$BROWSER`.
+    pub html_viewer: Option<String>,
+    pub writer: WriterConfig,
+    pub rules: Vec<TagRule>,
+    pub spam: SpamConfig,
+}
+
+fn expand(path: &Path) -> PathBuf {
+    let input = path.to_string_lossy();
+    shellexpand::full(&input)
+        .map(|p| PathBuf::from(p.into_owned()))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Returns the first existing file of a colon separated list of paths.
+pub fn resolve(search_path: &str) -> Option<PathBuf> {
+    search_path
+        .split(':')
+        .filter(|p| !p.is_empty())
+        .map(|p| expand(Path::new(p)))
+        .find(|p| p.is_file())
+}
+
+impl Config {
+    pub fn parse(input: &str, path: &Path) -> Result<Config, ConfigError> {
+        let mut config: Config =
+            toml::from_str(input).map_err(|e| ConfigError::CouldNotParse(path.to_path_buf(), e))?;
+        config.index_dir = config.index_dir.as_deref().map(expand);
+        config.maildirs.values_mut().for_each(|p| *p = expand(p));
+        Ok(config)
+    }
+
+    /// Loads the first existing file of `search_path`, or the defaults if there is none.
+    pub fn load(search_path: &str) -> Result<Config, ConfigError> {
+        match resolve(search_path) {
+            Some(path) => {
+                let input = fs::read_to_string(&path)
+                    .map_err(|e| ConfigError::CouldNotRead(path.clone(), e))?;
+                Config::parse(&input, &path)
+            }
+            None => Ok(Config::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve, Config};
+    use crate::readmail::display::OutputType;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn parses_a_full_config() {
+        let config = Config::parse(
+            r#"
+            index_dir = "/var/rms"
+            output = "summary"
+            html_width = 100
+
+            [templates]
+            brief = "{date:%Y-%m-%d} {subject}"
+
+            [maildirs]
+            work = "/mail/work"
+
+            [page_size]
+            latest = 5
+
+            [writer]
+            threads = 2
+
+            [[rules]]
+            query = "from:github.com"
+            tags = ["github", "-inbox"]
+
+            [spam]
+            threshold = 0.95
+            "#,
+            Path::new("config"),
+        )
+        .unwrap();
+        assert_eq!(config.index_dir, Some(PathBuf::from("/var/rms")));
+        assert_eq!(config.output, Some(OutputType::Summary));
+        assert_eq!(config.html_width, Some(100));
+        assert_eq!(config.templates.get("brief"), Some(&"{date:%Y-%m-%d} {subject}".to_string()));
+        assert_eq!(config.maildirs.get("work"), Some(&PathBuf::from("/mail/work")));
+        assert_eq!((config.page_size.search, config.page_size.latest), (100, 5));
+        assert_eq!((config.writer.threads, config.writer.memory_per_thread_mb), (Some(2), None));
+        assert_eq!(config.rules[0].tags, vec!["github", "-inbox"]);
+        assert_eq!(config.spam.threshold, 0.95);
+        assert!(Config::parse("output = \"nope\"", Path::new("config")).is_err());
+    }
+
+    #[test]
+    fn resolves_the_first_existing_file() {
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let search_path = format!("/does/not/exist:{}:/etc/passwd", manifest);
+        assert_eq!(resolve(&search_path), Some(PathBuf::from(manifest)));
+        assert_eq!(resolve("/does/not/exist"), None);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod cmd;
+pub mod config;
 pub mod message;
 pub mod readmail;
 pub mod stores;
@@ -0,0 +1,166 @@
+use mailparse::{addrparse, addrparse_header, MailAddr, MailAddrList, MailHeader};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+/// A mailbox of an address header such as `From`, `To` or `Cc`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "StoredAddress")]
+pub struct Address {
+    pub name: Option<String>,
+    pub email: String,
+}
+
+/// Messages stored before addresses were parsed hold the raw header value.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredAddress {
+    Raw(String),
+    Parsed {
+        #[serde(default)]
+        name: Option<String>,
+        email: String,
+    },
+}
+
+impl From<StoredAddress> for Address {
+    fn from(stored: StoredAddress) -> Address {
+        match stored {
+            StoredAddress::Raw(value) => Address::parse(&value),
+            StoredAddress::Parsed { name, email } => Address { name, email },
+        }
+    }
+}
+
+/// Deserializes a list of addresses, splitting the raw header values of messages stored before
+/// addresses were parsed into their mailboxes.
+pub fn deserialize_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Address>, D::Error> {
+    Ok(Vec::<StoredAddress>::deserialize(deserializer)?
+        .into_iter()
+        .flat_map(|stored| match stored {
+            StoredAddress::Raw(value) => Address::parse_list(&value),
+            parsed => vec![Address::from(parsed)],
+        })
+        .collect())
+}
+
+fn mailboxes(list: &MailAddrList) -> Vec<Address> {
+    list.iter()
+        .flat_map(|addr| match addr {
+            MailAddr::Single(info) => vec![info.clone()],
+            MailAddr::Group(group) => group.addrs.clone(),
+        })
+        .map(|info| Address {
+            name: info.display_name.filter(|n| !n.trim().is_empty()),
+            email: info.addr,
+        })
+        .collect()
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name.as_ref() {
+            Some(name) => write!(f, "{} <{}>", name, self.email),
+            None => write!(f, "{}", self.email),
+        }
+    }
+}
+
+impl Address {
+    /// Leniently parses a single `Name <email>` or bare `email` value.
+    pub fn parse(value: &str) -> Address {
+        let value = value.trim();
+        match (value.rfind('<'), value.rfind('>')) {
+            (Some(start), Some(end)) if start < end => {
+                let name = value[..start].trim().trim_matches('"').trim();
+                Address {
+                    name: Some(name.to_string()).filter(|n| !n.is_empty()),
+                    email: value[start + 1..end].trim().to_string(),
+                }
+            }
+            _ => Address {
+                name: None,
+                email: value.to_string(),
+            },
+        }
+    }
+
+    /// Parses every mailbox of an address header, including the members of groups. Values
+    /// that are not valid address lists are kept as a single address.
+    pub fn parse_header(header: &MailHeader) -> Vec<Address> {
+        match addrparse_header(header) {
+            Ok(list) => mailboxes(&list),
+            Err(_) => Address::parse_single(&header.get_value()),
+        }
+    }
+
+    /// Parses every mailbox of an address list value such as `a@example.com, B <b@example.com>`,
+    /// as `parse_header` does.
+    pub fn parse_list(value: &str) -> Vec<Address> {
+        match addrparse(value) {
+            Ok(list) => mailboxes(&list),
+            Err(_) => Address::parse_single(value),
+        }
+    }
+
+    fn parse_single(value: &str) -> Vec<Address> {
+        Some(value)
+            .filter(|v| !v.trim().is_empty())
+            .map(Address::parse)
+            .into_iter()
+            .collect()
+    }
+
+    pub fn name_or_email(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.email)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.email.is_empty() && self.name.is_none()
+    }
+}
+
+pub fn join(addresses: &[Address]) -> String {
+    addresses
+        .iter()
+        .map(Address::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::Address;
+    use crate::message::Message;
+
+    #[test]
+    fn parses_addresses_and_legacy_values() {
+        let alice = Address {
+            name: Some("Alice Doe".to_string()),
+            email: "alice@example.com".to_string(),
+        };
+        assert_eq!(Address::parse("\"Alice Doe\" <alice@example.com>"), alice);
+        assert_eq!(Address::parse(" bob@example.com ").name, None);
+        assert_eq!(alice.to_string(), "Alice Doe <alice@example.com>");
+
+        let stored: Address = serde_json::from_str("\"Alice Doe <alice@example.com>\"").unwrap();
+        assert_eq!(stored, alice);
+        let json = serde_json::to_string(&alice).unwrap();
+        assert_eq!(serde_json::from_str::<Address>(&json).unwrap(), alice);
+    }
+    #[test]
+    fn reads_the_recipients_of_legacy_messages() {
+        let stored = r#"{"id":"abc","body":[],"subject":"Hi","from":"Alice <alice@example.com>",
+            "recipients":["Bob <bob@example.com>, carol@example.com"],"date":0,"original":[],
+            "tags":[]}"#;
+        let msg: Message = serde_json::from_str(stored).unwrap();
+        let to = msg
+            .to
+            .iter()
+            .map(|a| a.email.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(to, ["bob@example.com", "carol@example.com"]);
+        assert_eq!(msg.to[0].name.as_deref(), Some("Bob"));
+    }
+}
@@ -0,0 +1,79 @@
+use super::{Address, Message};
+use chrono::prelude::*;
+use serde::Serialize;
+
+const EXCERPT_LEN: usize = 200;
+
+/// The message object printed by `--output json` and `--output jsonl`. Fields are only ever
+/// added, never renamed or removed:
+///
+/// * `id`: full message id, any unique prefix is accepted by the commands taking an id
+/// * `thread`: thread id, shared by all the messages of a conversation
+/// * `date`: RFC 3339 date in UTC, `timestamp` the same date in seconds since the epoch
+/// * `from`, `recipients`, `subject`: as displayed, `recipients` holding To, Cc and Bcc
+/// * `from_address`, `to`, `cc`, `bcc`, `reply_to`, `sender`: `{"name": ..., "email": ...}`
+///   objects, `name` being null when the header has none
+/// * `tags`: sorted tags
+/// * `maildir`, `folder`: where the message is stored, `folder` is `INBOX` for the maildir root
+/// * `excerpt`: the first characters of the text body, whitespace collapsed
+/// * `warnings`: problems met while decoding the message, such as an unknown charset
+/// * `spam_score`: from 0 to 1, given by the spam filter when indexed, null before it is trained
+/// * `depth`: only set by `rms thread`, nesting level of the reply
+#[derive(Debug, Serialize)]
+pub struct JsonMessage<'a> {
+    pub id: &'a str,
+    pub thread: &'a str,
+    pub date: String,
+    pub timestamp: u64,
+    pub from: String,
+    pub recipients: Vec<String>,
+    pub subject: &'a str,
+    pub from_address: &'a Address,
+    pub to: &'a [Address],
+    pub cc: &'a [Address],
+    pub bcc: &'a [Address],
+    pub reply_to: &'a [Address],
+    pub sender: Option<&'a Address>,
+    pub tags: Vec<&'a str>,
+    pub maildir: &'a str,
+    pub folder: &'a str,
+    pub excerpt: String,
+    pub warnings: &'a [String],
+    pub spam_score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<usize>,
+}
+
+impl<'a> JsonMessage<'a> {
+    pub fn new(msg: &'a Message) -> Self {
+        let mut tags = msg.tags.iter().map(String::as_str).collect::<Vec<&str>>();
+        tags.sort_unstable();
+        JsonMessage {
+            id: &msg.id,
+            thread: &msg.thread,
+            date: Utc.timestamp(msg.date as i64, 0).to_rfc3339(),
+            timestamp: msg.date,
+            from: msg.from.to_string(),
+            recipients: msg.recipients().map(Address::to_string).collect(),
+            subject: &msg.subject,
+            from_address: &msg.from,
+            to: &msg.to,
+            cc: &msg.cc,
+            bcc: &msg.bcc,
+            reply_to: &msg.reply_to,
+            sender: msg.sender.as_ref(),
+            tags,
+            maildir: &msg.maildir,
+            folder: &msg.folder,
+            excerpt: msg.excerpt(EXCERPT_LEN),
+            warnings: &msg.warnings,
+            spam_score: msg.spam_score,
+            depth: None,
+        }
+    }
+
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+}
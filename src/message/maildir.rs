@@ -1,5 +1,14 @@
 use async_stream::stream;
 use maildir_ext::Maildir;
+use std::fmt;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio_stream::{Stream, StreamExt};
 
 use crate::message::Message;
@@ -8,12 +17,35 @@ use super::MessageError;
 
 pub struct MailEntry(pub maildir_ext::MailEntry, pub bool);
 
+const INFO_SEPARATOR: &str = ":2,";
+pub const ROOT_FOLDER: &str = "INBOX";
+pub const UNREAD_TAG: &str = "unread";
+/// Maildir flags mirrored as tags. The seen flag (`S`) is mirrored inverted, as `unread`.
+const FLAG_TAGS: [(char, &str); 5] = [
+    ('D', "draft"),
+    ('F', "flagged"),
+    ('P', "passed"),
+    ('R', "replied"),
+    ('T', "trashed"),
+];
+
 #[derive(Debug)]
 pub enum MaildirError {
     FailedToReadMailEntry(String, std::io::Error),
     FailedToParseMailEntry(MessageError),
     FailedToMoveParsedMailEntry(String, std::io::Error),
 }
+
+impl fmt::Display for MaildirError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaildirError::FailedToReadMailEntry(s, e) => write!(f, "{}: {}", s, e),
+            MaildirError::FailedToParseMailEntry(e) => write!(f, "{}", e.message),
+            MaildirError::FailedToMoveParsedMailEntry(s, e) => write!(f, "{}: {}", s, e),
+        }
+    }
+}
+impl std::error::Error for MaildirError {}
 pub fn mailentry_iterator(
     source: &Maildir,
     full: bool,
@@ -46,19 +78,110 @@ fn mailentry_stream(
     }
 }
 
-pub fn parse_message(mail: MailEntry) -> Result<(Message, bool), MaildirError> {
+pub fn file_mtime(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+pub fn is_new(path: &Path) -> bool {
+    path.parent()
+        .and_then(|dir| dir.file_name())
+        .map_or(false, |dir| dir == "new")
+}
+
+/// Returns the Maildir++ folder of the message file at `path` in the maildir at `root`, `INBOX`
+/// for messages stored directly under the root. Only directories right under the root are
+/// folders, the root itself may be named with a dot, such as `~/.mail`.
+pub fn locate(path: &Path, root: &Path) -> String {
+    let name = path
+        .parent()
+        .and_then(Path::parent)
+        .filter(|dir| dir.parent() == Some(root))
+        .and_then(Path::file_name)
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match name.strip_prefix('.') {
+        Some(folder) if !folder.is_empty() => folder.to_string(),
+        _ => ROOT_FOLDER.to_string(),
+    }
+}
+
+pub fn set_location(message: &mut Message, path: &Path, root: &Path) {
+    message.maildir = root
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    message.folder = locate(path, root);
+}
+
+/// The directory of the Maildir++ `folder` of the maildir at `root`.
+pub fn folder_path(root: &Path, folder: &str) -> PathBuf {
+    if folder.is_empty() || folder == ROOT_FOLDER {
+        root.to_path_buf()
+    } else {
+        root.join(format!(".{}", folder))
+    }
+}
+
+/// Lists the maildir at `root` followed by its Maildir++ subfolders (`.Sent`, `.Archive`, ...).
+pub fn folders(root: &Path) -> Vec<PathBuf> {
+    let mut subfolders = fs::read_dir(root)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| {
+                    p.file_name()
+                        .map_or(false, |n| n.to_string_lossy().starts_with('.'))
+                        && p.join("cur").is_dir()
+                })
+                .collect::<Vec<PathBuf>>()
+        })
+        .unwrap_or_default();
+    subfolders.sort();
+    let mut all = vec![root.to_path_buf()];
+    all.append(&mut subfolders);
+    all
+}
+
+/// Parses the message stored at `path` in the maildir at `root`, e.g. a file reported by a
+/// filesystem watcher.
+pub fn parse_path(path: &Path, root: &Path) -> Result<(Message, bool), MaildirError> {
+    let data = fs::read(path).map_err(|e| {
+        MaildirError::FailedToReadMailEntry(format!("Failed to read {}", path.display()), e)
+    })?;
+    let mut message = Message::from_data(data).map_err(MaildirError::FailedToParseMailEntry)?;
+    message.path = Some(path.to_path_buf());
+    message.mtime = file_mtime(path);
+    set_location(&mut message, path, root);
+    sync_flag_tags(&mut message);
+    Ok((message, is_new(path)))
+}
+
+pub fn parse_message(mail: MailEntry, root: &Path) -> Result<(Message, bool), MaildirError> {
     let new = mail.1;
+    let path = mail.0.path().clone();
+    let mtime = file_mtime(&path);
     let message = Message::from_mailentry(mail);
     message
         .map_err(|e| MaildirError::FailedToParseMailEntry(e))
-        .map(|m| (m, new))
+        .map(|mut m| {
+            m.mtime = mtime;
+            set_location(&mut m, &path, root);
+            sync_flag_tags(&mut m);
+            (m, new)
+        })
 }
 
 fn message_stream(
     source: &Maildir,
     full: bool,
 ) -> impl Stream<Item = Result<(Message, bool), MaildirError>> {
-    mailentry_stream(source, full).map(|me| me.and_then(parse_message))
+    let root = source.path().to_path_buf();
+    mailentry_stream(source, full).map(move |me| me.and_then(|m| parse_message(m, &root)))
 }
 
 
@@ -89,3 +212,322 @@ where
             }
         })
 }
+
+pub fn split_info(path: &Path) -> (String, String) {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match name.split_once(INFO_SEPARATOR) {
+        Some((unique, flags)) => (unique.to_string(), flags.to_string()),
+        None => (name, String::new()),
+    }
+}
+
+pub fn flags_from_path(path: &Path) -> String {
+    split_info(path).1
+}
+
+pub fn set_flags(path: &Path, flags: &str) -> io::Result<PathBuf> {
+    let (unique, _) = split_info(path);
+    let mut flags = flags.chars().collect::<Vec<char>>();
+    flags.sort_unstable();
+    flags.dedup();
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let dir = if is_new(path) {
+        dir.with_file_name("cur")
+    } else {
+        dir
+    };
+    let target = dir.join(format!(
+        "{}{}{}",
+        unique,
+        INFO_SEPARATOR,
+        flags.into_iter().collect::<String>()
+    ));
+    if target != path {
+        fs::rename(path, &target)?;
+    }
+    Ok(target)
+}
+
+/// A file name no other delivery uses, in the `time.MusecPpidQn.host` form of the maildir
+/// spec.
+fn unique_name() -> String {
+    static DELIVERIES: AtomicUsize = AtomicUsize::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let host = fs::read_to_string("/etc/hostname")
+        .map(|h| h.trim().replace('/', "\\057").replace(':', "\\072"))
+        .ok()
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "localhost".to_string());
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        process::id(),
+        DELIVERIES.fetch_add(1, Ordering::Relaxed),
+        host
+    )
+}
+
+/// Delivers a message to the maildir folder at `dir` the way MDAs do: it is written and
+/// synced to `tmp/`, then renamed to `new/`, or to `cur/` when it has `flags`. Readers never
+/// see a partial message.
+pub fn deliver(dir: &Path, data: &[u8], flags: &str) -> io::Result<PathBuf> {
+    for sub in ["cur", "new", "tmp"] {
+        fs::create_dir_all(dir.join(sub))?;
+    }
+    let name = unique_name();
+    let tmp = dir.join("tmp").join(&name);
+    let target = if flags.is_empty() {
+        dir.join("new").join(&name)
+    } else {
+        let mut flags = flags.chars().collect::<Vec<char>>();
+        flags.sort_unstable();
+        flags.dedup();
+        let flags = flags.into_iter().collect::<String>();
+        dir.join("cur").join(format!("{}{}{}", name, INFO_SEPARATOR, flags))
+    };
+    let delivered = write_new(&tmp, data).and_then(|_| fs::rename(&tmp, &target));
+    if delivered.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    delivered.map(|_| target)
+}
+
+/// Writes `data` to a file that must not exist yet, synced to disk before returning.
+fn write_new(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    file.write_all(data).and_then(|_| file.sync_all())
+}
+
+/// Moves a message from `new/` to `cur/`, keeping its flags. Messages already in `cur/` are
+/// left untouched.
+pub fn move_to_cur(path: &Path) -> io::Result<PathBuf> {
+    if !is_new(path) {
+        return Ok(path.to_path_buf());
+    }
+    set_flags(path, &flags_from_path(path))
+}
+
+pub fn trash_file(path: &Path) -> io::Result<PathBuf> {
+    let mut flags = flags_from_path(path);
+    if !flags.contains('T') {
+        flags.push('T');
+    }
+    set_flags(path, &flags)
+}
+
+pub fn is_flag_tag(tag: &str) -> bool {
+    tag == UNREAD_TAG || FLAG_TAGS.iter().any(|(_, t)| *t == tag)
+}
+
+/// Tags matching the maildir `flags` of a file.
+pub fn flag_tags(flags: &str) -> HashSet<String> {
+    let mut tags = FLAG_TAGS
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, tag)| tag.to_string())
+        .collect::<HashSet<String>>();
+    if !flags.contains('S') {
+        tags.insert(UNREAD_TAG.to_string());
+    }
+    tags
+}
+
+/// Maildir flags matching `tags`. Flags of `current` without a tag equivalent are kept.
+pub fn tag_flags(tags: &HashSet<String>, current: &str) -> String {
+    let mut flags = current
+        .chars()
+        .filter(|c| *c != 'S' && !FLAG_TAGS.iter().any(|(flag, _)| flag == c))
+        .collect::<String>();
+    if !tags.contains(UNREAD_TAG) {
+        flags.push('S');
+    }
+    FLAG_TAGS
+        .iter()
+        .filter(|(_, tag)| tags.contains(*tag))
+        .for_each(|(flag, _)| flags.push(*flag));
+    flags
+}
+
+/// Replaces the flag tags of `msg` with the ones matching the flags of its file. Returns
+/// whether its tags changed.
+pub fn sync_flag_tags(msg: &mut Message) -> bool {
+    let flags = match msg.path.as_ref() {
+        Some(path) => flag_tags(&flags_from_path(path)),
+        None => return false,
+    };
+    let before = msg.tags.clone();
+    msg.tags.retain(|t| !is_flag_tag(t));
+    msg.tags.extend(flags);
+    before != msg.tags
+}
+
+/// Renames the file of `msg` so that its flags match its tags, letting other maildir clients
+/// see tags set in rms.
+pub fn sync_file_flags(msg: &mut Message) -> io::Result<()> {
+    if let Some(path) = msg.path.clone().filter(|p| p.exists()) {
+        let target = set_flags(&path, &tag_flags(&msg.tags, &flags_from_path(&path)))?;
+        if target != path {
+            msg.mtime = file_mtime(&target);
+            msg.path = Some(target);
+        }
+    }
+    Ok(())
+}
+
+/// How exported messages end up in the target maildir.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportMode {
+    Copy,
+    Hardlink,
+    Symlink,
+}
+
+#[derive(Debug)]
+pub struct ExportModeError(String);
+
+impl fmt::Display for ExportModeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown export mode {}, use copy, hardlink or symlink", self.0)
+    }
+}
+impl std::error::Error for ExportModeError {}
+
+impl std::str::FromStr for ExportMode {
+    type Err = ExportModeError;
+    fn from_str(input: &str) -> Result<ExportMode, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "copy" => Ok(ExportMode::Copy),
+            "hardlink" => Ok(ExportMode::Hardlink),
+            "symlink" => Ok(ExportMode::Symlink),
+            _ => Err(ExportModeError(input.to_string())),
+        }
+    }
+}
+
+/// Creates the folders of a maildir at `root`. Fails if the maildir already holds messages so
+/// that an export never mixes with other mail.
+pub fn create_maildir(root: &Path) -> io::Result<()> {
+    for sub in ["cur", "new", "tmp"] {
+        let dir = root.join(sub);
+        fs::create_dir_all(&dir)?;
+        if sub != "tmp" && fs::read_dir(&dir)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is not empty", dir.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// File name of an exported message, with the flags matching its tags.
+fn export_name(id: &str, tags: &HashSet<String>) -> String {
+    let mut flags = tag_flags(tags, "").chars().collect::<Vec<char>>();
+    flags.sort_unstable();
+    format!("{}{}{}", id, INFO_SEPARATOR, flags.into_iter().collect::<String>())
+}
+
+/// Puts `msg` in the `cur/` folder of the maildir at `root`, through `tmp/` as a delivery
+/// would. Links point to the file of the message, its original content is written instead when
+/// the file is unknown or gone. A file that can't be hardlinked, such as one on another
+/// filesystem, is copied.
+pub fn export_message(msg: &Message, root: &Path, mode: ExportMode) -> io::Result<PathBuf> {
+    let tmp = root.join("tmp").join(unique_name());
+    let target = root.join("cur").join(export_name(&msg.id, &msg.tags));
+    let source = msg.path.as_ref().filter(|p| p.exists());
+    let placed = match (mode, source) {
+        (ExportMode::Hardlink, Some(source)) => {
+            fs::hard_link(source, &tmp).or_else(|_| fs::copy(source, &tmp).map(|_| ()))
+        }
+        (ExportMode::Symlink, Some(source)) => {
+            fs::canonicalize(source).and_then(|source| symlink(source, &tmp))
+        }
+        _ => write_new(&tmp, &msg.original),
+    };
+    let exported = placed.and_then(|_| fs::rename(&tmp, &target));
+    if exported.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    exported.map(|_| target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        create_maildir, export_message, export_name, flag_tags, locate, tag_flags, ExportMode,
+    };
+    use crate::message::Message;
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn maps_flags_to_tags_and_back() {
+        let tags = flag_tags("FRS");
+        let expected = ["flagged", "replied"].iter().map(|t| t.to_string()).collect();
+        assert_eq!(tags, expected);
+        assert_eq!(flag_tags(""), ["unread".to_string()].into_iter().collect());
+
+        let mut tags = tags;
+        tags.insert("unread".to_string());
+        tags.insert("inbox".to_string());
+        tags.remove("replied");
+        assert_eq!(tag_flags(&tags, "RSa"), "aF");
+        assert_eq!(tag_flags(&HashSet::new(), ""), "S");
+    }
+
+    #[test]
+    fn locates_folders_under_the_root() {
+        let root = Path::new("/home/me/.mail");
+        assert_eq!(locate(&root.join("cur/1:2,S"), root), "INBOX");
+        assert_eq!(locate(&root.join("new/1"), root), "INBOX");
+        assert_eq!(locate(&root.join(".Lists.rust/cur/1:2,S"), root), "Lists.rust");
+        let nested = Path::new("/home/me/.mail/.Archive/cur/1:2,S");
+        assert_eq!(locate(nested, Path::new("/home/me/.mail/.Archive")), "INBOX");
+    }
+
+    #[test]
+    fn names_exported_messages_after_their_tags() {
+        let tags = ["inbox", "replied", "flagged"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        assert_eq!(export_name("abc", &tags), "abc:2,FRS");
+        let unread = ["unread".to_string()].into_iter().collect();
+        assert_eq!(export_name("abc", &unread), "abc:2,");
+    }
+    #[test]
+    fn exports_messages_through_tmp() {
+        let dir = TempDir::new("rms-export").unwrap();
+        let source = dir.path().join("source");
+        fs::write(&source, "Subject: hi\n\nhello\n").unwrap();
+        let root = dir.path().join("export");
+        create_maildir(&root).unwrap();
+        let msg = Message {
+            id: "abc".to_string(),
+            path: Some(source),
+            original: b"Subject: hi\n\nhello\n".to_vec(),
+            ..Default::default()
+        };
+        for (id, mode) in [("copy", ExportMode::Copy), ("link", ExportMode::Hardlink)] {
+            let msg = Message {
+                id: id.to_string(),
+                ..msg.clone()
+            };
+            let exported = export_message(&msg, &root, mode).unwrap();
+            assert_eq!(exported, root.join("cur").join(format!("{}:2,S", id)));
+            assert_eq!(fs::read(&exported).unwrap(), msg.original);
+        }
+        assert_eq!(fs::read_dir(root.join("tmp")).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,269 @@
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+
+fn is_from_line(line: &[u8]) -> bool {
+    line.starts_with(b"From ")
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line == b"\n" || line == b"\r\n"
+}
+
+/// Whether `line` is a `From ` line quoted by any number of `>`.
+fn is_quoted_from(line: &[u8]) -> bool {
+    let quotes = line.iter().take_while(|b| **b == b'>').count();
+    is_from_line(&line[quotes..])
+}
+
+fn content_length(line: &[u8]) -> Option<usize> {
+    let line = String::from_utf8_lossy(line);
+    let (name, value) = line.split_once(':')?;
+    if name.trim().eq_ignore_ascii_case("content-length") {
+        value.trim().parse().ok()
+    } else {
+        None
+    }
+}
+
+fn split_lines(data: &[u8]) -> Vec<Vec<u8>> {
+    data.split_inclusive(|b| *b == b'\n')
+        .map(|line| line.to_vec())
+        .collect()
+}
+
+/// The messages of an mbox, read one at a time.
+///
+/// Messages start at `From ` lines. Quoted `>From ` lines are unquoted once, as written by
+/// mboxrd writers. A message with a `Content-Length` header, as written by mboxcl2 writers,
+/// has its body read as is when the length ends right before the next message.
+pub struct Mbox<R> {
+    reader: R,
+    /// Lines read ahead of the message being read.
+    backlog: VecDeque<Vec<u8>>,
+}
+
+impl<R: BufRead> Mbox<R> {
+    pub fn new(reader: R) -> Self {
+        Mbox {
+            reader,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(line) = self.backlog.pop_front() {
+            return Ok(Some(line));
+        }
+        let mut line = vec![];
+        match self.reader.read_until(b'\n', &mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+
+    fn push_back(&mut self, lines: Vec<Vec<u8>>) {
+        lines
+            .into_iter()
+            .rev()
+            .for_each(|line| self.backlog.push_front(line));
+    }
+
+    fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        while data.len() < len {
+            let line = match self.backlog.pop_front() {
+                Some(line) => line,
+                None => break,
+            };
+            let missing = len - data.len();
+            if line.len() > missing {
+                data.extend_from_slice(&line[..missing]);
+                self.backlog.push_front(line[missing..].to_vec());
+            } else {
+                data.extend(line);
+            }
+        }
+        let missing = (len - data.len()) as u64;
+        (&mut self.reader).take(missing).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Reads `len` bytes of body. Returns them if the next message, or the end of the mbox,
+    /// follows right after. Puts everything back otherwise.
+    fn read_counted_body(&mut self, len: usize) -> io::Result<Option<Vec<u8>>> {
+        let body = self.read_bytes(len)?;
+        let mut after = vec![];
+        let valid = body.len() == len
+            && loop {
+                match self.next_line()? {
+                    None => break true,
+                    Some(line) if is_from_line(&line) => {
+                        after.push(line);
+                        break true;
+                    }
+                    Some(line) if is_blank(&line) && after.is_empty() => after.push(line),
+                    Some(line) => {
+                        after.push(line);
+                        break false;
+                    }
+                }
+            };
+        if valid {
+            // The blank line separating messages is not part of the body.
+            after.retain(|line| !is_blank(line));
+            self.push_back(after);
+            Ok(Some(body))
+        } else {
+            let mut lines = split_lines(&body);
+            lines.extend(after);
+            self.push_back(lines);
+            Ok(None)
+        }
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        // Skips the From line, and anything before the first one.
+        loop {
+            match self.next_line()? {
+                None => return Ok(None),
+                Some(line) if is_from_line(&line) => break,
+                Some(_) => {}
+            }
+        }
+        let mut message = vec![];
+        let mut length = None;
+        while let Some(line) = self.next_line()? {
+            if is_from_line(&line) {
+                self.push_back(vec![line]);
+                return Ok(Some(message));
+            }
+            let end_of_headers = is_blank(&line);
+            length = length.or_else(|| content_length(&line));
+            message.extend(line);
+            if end_of_headers {
+                break;
+            }
+        }
+        if let Some(len) = length {
+            if let Some(body) = self.read_counted_body(len)? {
+                message.extend(body);
+                return Ok(Some(message));
+            }
+        }
+        // A blank line is only part of the message if another line follows before the next
+        // From line.
+        let mut blank: Option<Vec<u8>> = None;
+        while let Some(line) = self.next_line()? {
+            if is_from_line(&line) {
+                self.push_back(vec![line]);
+                break;
+            }
+            if let Some(blank) = blank.take() {
+                message.extend(blank);
+            }
+            if is_blank(&line) {
+                blank = Some(line);
+            } else if is_quoted_from(&line) {
+                message.extend_from_slice(&line[1..]);
+            } else {
+                message.extend(line);
+            }
+        }
+        Ok(Some(message))
+    }
+}
+
+impl<R: BufRead> Iterator for Mbox<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+/// Appends a message to an mbox in the mboxrd format: a `From ` line with the sender and the
+/// date, the message with its `From ` lines quoted and a blank line.
+pub fn write_message<W: Write>(
+    out: &mut W,
+    data: &[u8],
+    sender: &str,
+    date: u64,
+) -> io::Result<()> {
+    let sender = sender.split_whitespace().next().unwrap_or("MAILER-DAEMON");
+    let date = Utc.timestamp(date as i64, 0).format("%a %b %e %H:%M:%S %Y");
+    writeln!(out, "From {} {}", sender, date)?;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        if is_quoted_from(line) {
+            out.write_all(b">")?;
+        }
+        out.write_all(line)?;
+    }
+    if !data.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    out.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_message, Mbox};
+    use std::io::Cursor;
+
+    fn read(mbox: &str) -> Vec<String> {
+        Mbox::new(Cursor::new(mbox.as_bytes().to_vec()))
+            .map(|m| String::from_utf8(m.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reads_mboxrd_and_mboxcl2() {
+        let messages = read(
+            "From a@example.com Thu Mar 14 09:30:00 2024\n\
+             Subject: one\n\
+             \n\
+             >From the start\n\
+             >>From quoted\n\
+             \n\
+             \n\
+             From b@example.com Thu Mar 14 09:31:00 2024\n\
+             Subject: two\n\
+             Content-Length: 18\n\
+             \n\
+             From here\n\
+             >From x\n\
+             \n\
+             From c@example.com Thu Mar 14 09:32:00 2024\n\
+             Subject: three\n\
+             Content-Length: 2\n\
+             \n\
+             wrong length\n",
+        );
+        assert_eq!(
+            messages,
+            vec![
+                "Subject: one\n\nFrom the start\n>From quoted\n\n",
+                "Subject: two\nContent-Length: 18\n\nFrom here\n>From x\n",
+                "Subject: three\nContent-Length: 2\n\nwrong length\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let mut out = vec![];
+        let original = "Subject: hi\n\nFrom me\n>From you";
+        write_message(&mut out, original.as_bytes(), "a@example.com", 0).unwrap();
+        write_message(&mut out, b"Subject: again\n\nbye\n", "", 0).unwrap();
+        let mbox = String::from_utf8(out).unwrap();
+        assert!(mbox.starts_with("From a@example.com Thu Jan  1 00:00:00 1970\n"));
+        assert!(mbox.contains("\nFrom MAILER-DAEMON Thu Jan  1 00:00:00 1970\n"));
+        assert_eq!(
+            read(&mbox),
+            vec![
+                "Subject: hi\n\nFrom me\n>From you\n",
+                "Subject: again\n\nbye\n"
+            ]
+        );
+    }
+}
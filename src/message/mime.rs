@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A node of the MIME structure of a message. Only the structure is kept, the content of a
+/// part is read from the original message when needed.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MimePart {
+    pub content_type: String,
+    /// Content-Type parameters such as `charset` or `boundary`, RFC 2231 values decoded.
+    pub params: BTreeMap<String, String>,
+    /// `inline` or `attachment` when the part has a Content-Disposition.
+    pub disposition: Option<String>,
+    pub filename: Option<String>,
+    pub content_id: Option<String>,
+    /// Decoded size in bytes, the sum of the children for containers.
+    pub size: usize,
+    /// Subparts of a multipart, or the attached message of a `message/rfc822` part.
+    pub parts: Vec<MimePart>,
+}
+
+/// A part along with its IMAP style number (`1.2`) and its path of child indices in the tree.
+pub struct NumberedPart<'a> {
+    pub number: String,
+    pub path: Vec<usize>,
+    pub depth: usize,
+    pub part: &'a MimePart,
+}
+
+fn join(number: &str, i: usize) -> String {
+    if number.is_empty() {
+        i.to_string()
+    } else {
+        format!("{}.{}", number, i)
+    }
+}
+
+impl MimePart {
+    pub fn is_multipart(&self) -> bool {
+        self.content_type.starts_with("multipart/")
+    }
+
+    pub fn is_message(&self) -> bool {
+        self.content_type == "message/rfc822"
+    }
+
+    fn walk<'a>(
+        &'a self,
+        number: String,
+        path: Vec<usize>,
+        depth: usize,
+        out: &mut Vec<NumberedPart<'a>>,
+    ) {
+        for (i, child) in self.parts.iter().enumerate() {
+            // The multipart body of an attached message shares the number of the attachment,
+            // its subparts are numbered right under it.
+            let child_number = match (self.is_message(), child.is_multipart()) {
+                (true, true) => number.clone(),
+                (true, false) => join(&number, 1),
+                (false, _) => join(&number, i + 1),
+            };
+            let mut child_path = path.clone();
+            child_path.push(i);
+            out.push(NumberedPart {
+                number: child_number.clone(),
+                path: child_path.clone(),
+                depth: depth + 1,
+                part: child,
+            });
+            child.walk(child_number, child_path, depth + 1, out);
+        }
+    }
+
+    /// Every part in document order, numbered the way IMAP does: the top level multipart has
+    /// no number, its subparts are `1`, `2`..., the subparts of `2` are `2.1`, `2.2`... A
+    /// message made of a single part has it as part `1`.
+    pub fn numbered(&self) -> Vec<NumberedPart<'_>> {
+        let number = if self.is_multipart() {
+            String::new()
+        } else {
+            "1".to_string()
+        };
+        let mut out = vec![NumberedPart {
+            number: number.clone(),
+            path: vec![],
+            depth: 0,
+            part: self,
+        }];
+        self.walk(number, vec![], 0, &mut out);
+        out
+    }
+
+    pub fn find(&self, number: &str) -> Option<NumberedPart<'_>> {
+        self.numbered().into_iter().find(|p| p.number == number)
+    }
+
+    /// One line per part: number, content type and what else is known about it.
+    pub fn outline(&self) -> String {
+        self.numbered()
+            .iter()
+            .map(|numbered| {
+                let part = numbered.part;
+                let mut line = format!(
+                    "{:<8}{}{}",
+                    numbered.number,
+                    "  ".repeat(numbered.depth),
+                    part.content_type
+                );
+                if let Some(charset) = part.params.get("charset") {
+                    line.push_str(&format!("; charset={}", charset));
+                }
+                if let Some(disposition) = part.disposition.as_ref() {
+                    line.push_str(&format!(" [{}]", disposition));
+                }
+                if let Some(filename) = part.filename.as_ref() {
+                    line.push_str(&format!(" \"{}\"", filename));
+                }
+                if let Some(id) = part.content_id.as_ref() {
+                    line.push_str(&format!(" <{}>", id));
+                }
+                line.push_str(&format!(" {} bytes", part.size));
+                line
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MimePart;
+
+    fn part(content_type: &str, parts: Vec<MimePart>) -> MimePart {
+        MimePart {
+            content_type: content_type.to_string(),
+            parts,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn numbers_parts_like_imap() {
+        let forwarded = part(
+            "message/rfc822",
+            vec![part(
+                "multipart/alternative",
+                vec![part("text/plain", vec![]), part("text/html", vec![])],
+            )],
+        );
+        let tree = part(
+            "multipart/mixed",
+            vec![
+                part(
+                    "multipart/alternative",
+                    vec![part("text/plain", vec![]), part("text/html", vec![])],
+                ),
+                forwarded,
+                part("application/pdf", vec![]),
+            ],
+        );
+        let numbers = tree
+            .numbered()
+            .into_iter()
+            .map(|p| (p.number, p.part.content_type.as_str()))
+            .collect::<Vec<(String, &str)>>();
+        let expected = vec![
+            ("", "multipart/mixed"),
+            ("1", "multipart/alternative"),
+            ("1.1", "text/plain"),
+            ("1.2", "text/html"),
+            ("2", "message/rfc822"),
+            ("2", "multipart/alternative"),
+            ("2.1", "text/plain"),
+            ("2.2", "text/html"),
+            ("3", "application/pdf"),
+        ];
+        assert_eq!(
+            numbers,
+            expected
+                .into_iter()
+                .map(|(n, t)| (n.to_string(), t))
+                .collect::<Vec<(String, &str)>>()
+        );
+        let found = tree.find("2.2").unwrap();
+        assert_eq!(
+            (found.path, found.part.content_type.as_str()),
+            (vec![1, 0, 1], "text/html")
+        );
+        assert_eq!(tree.find("2").unwrap().part.content_type, "message/rfc822");
+        assert!(tree.find("4").is_none());
+        assert_eq!(
+            part("text/plain", vec![]).find("1").unwrap().path,
+            Vec::<usize>::new()
+        );
+    }
+}
@@ -1,15 +1,28 @@
+pub mod address;
+pub mod json;
 pub mod maildir;
+pub mod mbox;
+pub mod mime;
+pub mod thread;
 use crate::readmail;
+pub use address::Address;
 use crate::readmail::html2text;
+use crate::readmail::sanitize::sanitize;
 use chrono::prelude::*;
 use maildir::MailEntry;
-use mailparse::{dateparse, parse_mail, ParsedMail};
+use mailparse::{dateparse, parse_mail, MailHeaderMap, ParsedMail};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha512};
 use std::collections::HashSet;
 use std::convert::AsRef;
 use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use crate::readmail::display::{DisplayAs, OutputType};
+use crate::readmail::template::{FieldValue, TemplateFields};
+use json::JsonMessage;
+use mime::MimePart;
+use std::borrow::Cow;
 
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -68,22 +81,77 @@ impl Body {
     pub fn as_text(&self) -> String {
         match self.mime {
             Mime::PlainText => self.value.clone(),
-            Mime::Html => html2text(self.value.as_str()), //.clone(), //from_read(self.value.as_bytes(), 80),
+            Mime::Html => html2text(self.value.as_str()),
             _ => "".to_string(),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub size: usize,
+    pub content_id: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Message {
     pub id: String,
     pub body: Vec<Body>,
     pub subject: String,
-    pub from: String,
-    pub recipients: Vec<String>,
+    pub from: Address,
+    /// Messages stored before addresses were parsed have their `To`, `Cc` and `Bcc` merged in
+    /// `recipients`, read here.
+    #[serde(
+        default,
+        alias = "recipients",
+        deserialize_with = "address::deserialize_list"
+    )]
+    pub to: Vec<Address>,
+    #[serde(default)]
+    pub cc: Vec<Address>,
+    #[serde(default)]
+    pub bcc: Vec<Address>,
+    #[serde(default)]
+    pub reply_to: Vec<Address>,
+    #[serde(default)]
+    pub sender: Option<Address>,
     pub date: u64,
     pub original: Vec<u8>,
     pub tags: HashSet<String>,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub mtime: u64,
+    #[serde(default)]
+    pub message_id: String,
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
+    #[serde(default)]
+    pub references: Vec<String>,
+    #[serde(default)]
+    pub thread: String,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    #[serde(default)]
+    pub maildir: String,
+    #[serde(default)]
+    pub folder: String,
+    /// Identifier of the mailing list the message came through, from its List-Id header.
+    #[serde(default)]
+    pub list_id: Option<String>,
+    /// Probability of being spam given by the spam filter when the message was indexed.
+    #[serde(default)]
+    pub spam_score: Option<f64>,
+    /// Whether the `spam` tag was set by the spam filter rather than the user.
+    #[serde(default)]
+    pub auto_spam: bool,
+    /// Problems met while decoding the message, such as an unknown charset.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    #[serde(default)]
+    pub mime: MimePart,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -116,6 +184,62 @@ impl fmt::Display for ShortMessage {
     }
 }
 
+impl TemplateFields for ShortMessage {
+    fn field(&self, name: &str) -> Option<FieldValue> {
+        let text = match name {
+            "id" => self.id.clone(),
+            "short_id" => self.id.chars().take(24).collect(),
+            "date" => return Some(FieldValue::Date(self.date)),
+            "from" => self.from.clone(),
+            "from.name" => Address::parse(&self.from).name_or_email().to_string(),
+            "from.email" => Address::parse(&self.from).email,
+            "subject" => self.subject.clone(),
+            _ => return None,
+        };
+        Some(FieldValue::Text(text))
+    }
+}
+
+impl TemplateFields for Message {
+    fn field(&self, name: &str) -> Option<FieldValue> {
+        let text = match name {
+            "id" => self.id.clone(),
+            "short_id" => self.id.chars().take(24).collect(),
+            "thread" => self.thread.clone(),
+            "date" => return Some(FieldValue::Date(self.date)),
+            "from" => self.from.to_string(),
+            "from.name" => self.from.name_or_email().to_string(),
+            "from.email" => self.from.email.clone(),
+            "to" => address::join(&self.to),
+            "cc" => address::join(&self.cc),
+            "subject" => self.subject.clone(),
+            "tags" => {
+                let mut tags = self.tags.iter().cloned().collect::<Vec<String>>();
+                tags.sort();
+                tags.join(",")
+            }
+            "maildir" => self.maildir.clone(),
+            "folder" => self.folder.clone(),
+            "excerpt" => self.excerpt(80),
+            "body" if self.body.is_empty() => String::new(),
+            "body" => self.get_body(None).as_text(),
+            "path" => self
+                .path
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            "attachments" => self
+                .attachments
+                .iter()
+                .map(|a| a.filename.as_str())
+                .collect::<Vec<&str>>()
+                .join(", "),
+            _ => return None,
+        };
+        Some(FieldValue::Text(text))
+    }
+}
+
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.display(&OutputType::Short))
@@ -133,24 +257,20 @@ impl DisplayAs for Message {
         match t {
             OutputType::Short => format!("{} | {} | {}", self.short_id(), dstr, self.subject.as_str()),
             OutputType::Full => format!(
-            r#"
-        From: {}
-        to/cc/bcc: {}
-        Date: {}
-        Subject: {}
-
-        {} 
-        # {} 
-        "#,
-            self.from,
-            self.recipients.join(","),
-            dstr,
-            self.subject,
-self.get_body(None).as_text(), self.id
-        ),
+                "From: {}\nTo: {}\nCc: {}\nDate: {}\nSubject: {}\n\n{}\n# {}",
+                self.from,
+                address::join(&self.to),
+                address::join(&self.cc),
+                dstr,
+                self.subject,
+                self.get_body(None).as_text(),
+                self.id
+            ),
             OutputType::Html => format!("{}", self.get_body(Some(Mime::Html)).as_text()),
             OutputType::Summary => format!("{} | {} [{}]", dstr, self.subject.as_str(), self.from),
             OutputType::Raw => String::from_utf8(self.original.clone()).unwrap_or(String::from("BAD FILE, please open an issue")),
+            OutputType::Json => serde_json::to_string_pretty(&JsonMessage::new(self)).unwrap_or_default(),
+            OutputType::Jsonl => serde_json::to_string(&JsonMessage::new(self)).unwrap_or_default(),
         }
     }
 }
@@ -174,19 +294,29 @@ impl Message {
         let original = Vec::from(msg.data);
         let headers = &msg.headers;
         let mut subject: String = "".to_string();
-        let mut from: String = "".to_string();
-        let mut recipients: Vec<String> = vec![];
+        let mut from = Address::default();
+        let mut to = vec![];
+        let mut cc = vec![];
+        let mut bcc = vec![];
+        let mut reply_to = vec![];
+        let mut sender = None;
         let default_date = 0;
         let mut date = default_date;
         for h in headers {
-            let key = h.get_key();
+            let key = h.get_key().to_lowercase();
             match key.as_ref() {
-                "Subject" => subject = h.get_value(),
-                "From" => from = h.get_value(),
-                "To" => recipients.push(h.get_value()),
-                "cc" => recipients.push(h.get_value()),
-                "bcc" => recipients.push(h.get_value()),
-                "Received" | "Date" => {
+                "subject" => subject = h.get_value(),
+                "from" => {
+                    if let Some(first) = Address::parse_header(h).into_iter().next() {
+                        from = first;
+                    }
+                }
+                "to" => to.extend(Address::parse_header(h)),
+                "cc" => cc.extend(Address::parse_header(h)),
+                "bcc" => bcc.extend(Address::parse_header(h)),
+                "reply-to" => reply_to.extend(Address::parse_header(h)),
+                "sender" => sender = Address::parse_header(h).into_iter().next(),
+                "received" | "date" => {
                     if date == default_date {
                         let date_str = h.get_value();
                         let date_str = date_str
@@ -204,16 +334,56 @@ impl Message {
                 _ => {}
             }
         }
-        let bodies = readmail::extract_body(&msg, false);
+        let message_id = headers
+            .get_first_value("Message-ID")
+            .and_then(|v| thread::parse_message_ids(&v).into_iter().next())
+            .unwrap_or_default();
+        let in_reply_to = headers
+            .get_first_value("In-Reply-To")
+            .and_then(|v| thread::parse_message_ids(&v).into_iter().last());
+        let references = headers
+            .get_first_value("References")
+            .map(|v| thread::parse_message_ids(&v))
+            .unwrap_or_default();
+        // `Rust users <rust-users.lists.rust-lang.org>`, the id being between the brackets.
+        let list_id = headers.get_first_value("List-Id").and_then(|v| {
+            let id = match (v.rfind('<'), v.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &v[start + 1..end],
+                _ => v.as_str(),
+            };
+            Some(id.trim().to_lowercase()).filter(|id| !id.is_empty())
+        });
+        let mut warnings = vec![];
+        let bodies = readmail::extract_body(&msg, false, &mut warnings);
+        let attachments = readmail::extract_attachments(&msg);
+        let mime = readmail::mime_tree(&msg);
         Ok(Message {
             body: bodies,
             from,
             subject,
-            recipients,
+            to,
+            cc,
+            bcc,
+            reply_to,
+            sender,
             date: date as u64,
             id,
             original,
             tags: HashSet::new(),
+            path: None,
+            mtime: 0,
+            message_id,
+            in_reply_to,
+            references,
+            thread: String::new(),
+            attachments,
+            maildir: String::new(),
+            folder: String::new(),
+            list_id,
+            spam_score: None,
+            auto_spam: false,
+            warnings,
+            mime,
         })
     }
     pub fn from_data(data: Vec<u8>) -> Result<Self, MessageError> {
@@ -223,8 +393,12 @@ impl Message {
         Self::from_parsedmail(&parsed_mail)
     }
     pub fn from_mailentry(mut mailentry: MailEntry) -> Result<Self, MessageError> {
+        let path = mailentry.0.path().clone();
         match mailentry.0.parsed() {
-            Ok(parsed) => Self::from_parsedmail(&parsed),
+            Ok(parsed) => Self::from_parsedmail(&parsed).map(|mut msg| {
+                msg.path = Some(path);
+                msg
+            }),
             Err(_) => Err(MessageError {
                 message: format!("Failed to parse email id {}", mailentry.0.id()),
             }),
@@ -235,9 +409,116 @@ impl Message {
         self.body.iter().find(|b| b.mime == m ).unwrap_or(self.body.get(0).unwrap())
     }
 
+    pub fn has_attachment(&self) -> bool {
+        !self.attachments.is_empty()
+    }
+
+    /// Saves the attachments named `name`, or all of them, into `dir`.
+    pub fn save_attachments(
+        &self,
+        name: Option<&str>,
+        dir: &Path,
+    ) -> Result<Vec<PathBuf>, MessageError> {
+        let parsed = parse_mail(self.original.as_slice())
+            .map_err(|_| MessageError::from("Unable to parse email data"))?;
+        readmail::save_attachments(&parsed, name, dir).map_err(|e| MessageError {
+            message: format!("Unable to save attachments: {}", e),
+        })
+    }
+
+    /// The MIME structure, read from the original message if it was stored without it.
+    pub fn mime_tree(&self) -> Result<Cow<'_, MimePart>, MessageError> {
+        if !self.mime.content_type.is_empty() {
+            return Ok(Cow::Borrowed(&self.mime));
+        }
+        let parsed = parse_mail(self.original.as_slice())
+            .map_err(|_| MessageError::from("Unable to parse email data"))?;
+        Ok(Cow::Owned(readmail::mime_tree(&parsed)))
+    }
+
+    /// The content of the part numbered `number` as in [`MimePart::numbered`]. Text parts are
+    /// converted to UTF-8, other parts are returned as they were attached.
+    pub fn part_content(&self, number: &str) -> Result<Vec<u8>, MessageError> {
+        let tree = self.mime_tree()?;
+        let found = tree.find(number).ok_or_else(|| MessageError {
+            message: format!("No part {}", number),
+        })?;
+        let parsed = parse_mail(self.original.as_slice())
+            .map_err(|_| MessageError::from("Unable to parse email data"))?;
+        let content = readmail::part_content(&parsed, &found.path).ok_or_else(|| MessageError {
+            message: format!("Unable to read part {}", number),
+        })?;
+        if found.part.content_type.starts_with("text/") {
+            let charset = found.part.params.get("charset").map_or("", String::as_str);
+            return Ok(readmail::charset::decode_text(&content, charset).0.into_bytes());
+        }
+        Ok(content)
+    }
+
+    /// Writes the sanitized html body into `dir` along with the inline parts it shows, see
+    /// [`readmail::sanitize::sanitize`]. Returns the path of the html file.
+    pub fn write_html(&self, dir: &Path, remote: bool) -> Result<PathBuf, MessageError> {
+        let body = self
+            .body
+            .iter()
+            .find(|b| b.mime == Mime::Html)
+            .ok_or_else(|| MessageError::from("The message has no html part"))?;
+        let parsed = parse_mail(self.original.as_slice())
+            .map_err(|_| MessageError::from("Unable to parse email data"))?;
+        let tree = self.mime_tree()?;
+        let inline = readmail::save_inline_parts(&parsed, &tree, dir).map_err(|e| MessageError {
+            message: format!("Unable to save inline parts: {}", e),
+        })?;
+        let path = dir.join("message.html");
+        fs::write(&path, sanitize(&body.value, remote, &inline)).map_err(|e| MessageError {
+            message: format!("Unable to write {}: {}", path.display(), e),
+        })?;
+        Ok(path)
+    }
+
+    /// The first `len` characters of the text body, whitespace collapsed.
+    pub fn excerpt(&self, len: usize) -> String {
+        if self.body.is_empty() {
+            return String::new();
+        }
+        self.get_body(None)
+            .as_text()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+            .chars()
+            .take(len)
+            .collect()
+    }
+
+    /// To, Cc and Bcc addresses.
+    pub fn recipients(&self) -> impl Iterator<Item = &Address> {
+        self.to.iter().chain(self.cc.iter()).chain(self.bcc.iter())
+    }
+
     pub fn short_id(&self) -> &str{
         &self.id[..24]
     }
+
+    /// Applies tag changes of the form `+tag`, `-tag` or `tag` (same as `+tag`).
+    /// Returns whether the message's tags changed.
+    pub fn apply_tags(&mut self, changes: &HashSet<String>) -> bool {
+        let before = self.tags.clone();
+        for change in changes {
+            match change.strip_prefix('-') {
+                Some(tag) => {
+                    self.tags.remove(tag);
+                }
+                None => {
+                    let tag = change.strip_prefix('+').unwrap_or(change);
+                    if !tag.is_empty() {
+                        self.tags.insert(tag.to_string());
+                    }
+                }
+            }
+        }
+        before != self.tags
+    }
 }
 #[allow(dead_code)]
 impl MessageBuilder {
@@ -278,12 +559,18 @@ impl MessageBuilder {
                 mime: Mime::PlainText,
                 value: self.body.expect(msg),
             }],
-            from: self.from.expect(msg),
+            from: Address::parse(&self.from.expect(msg)),
             subject: self.subject.expect(msg),
-            recipients: self.recipients.expect(msg),
+            to: self
+                .recipients
+                .expect(msg)
+                .iter()
+                .map(|r| Address::parse(r))
+                .collect(),
             date: self.date.expect(msg),
             original: self.original.expect(msg),
             tags: HashSet::new(),
+            ..Default::default()
         }
     }
 }
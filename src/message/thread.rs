@@ -0,0 +1,155 @@
+use super::{get_id, Message};
+use std::collections::{HashMap, HashSet};
+
+/// Extracts the `<id>` values of a Message-ID, In-Reply-To or References header.
+pub fn parse_message_ids(value: &str) -> Vec<String> {
+    let mut ids = vec![];
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) => {
+                let id = rest[start + 1..start + end].trim();
+                if !id.is_empty() {
+                    ids.push(id.to_string());
+                }
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    if ids.is_empty() {
+        ids = value.split_whitespace().map(String::from).collect();
+    }
+    ids
+}
+
+/// Message-IDs this message points at, oldest ancestor first.
+pub fn ancestors(msg: &Message) -> Vec<&String> {
+    let mut ids = msg.references.iter().collect::<Vec<&String>>();
+    if let Some(parent) = msg.in_reply_to.as_ref() {
+        if !ids.contains(&parent) {
+            ids.push(parent);
+        }
+    }
+    ids
+}
+
+/// Thread id derived from the conversation root, used when no related message is known yet.
+pub fn root_thread_id(msg: &Message) -> String {
+    let root = ancestors(msg)
+        .first()
+        .map(|id| id.as_str())
+        .or_else(|| Some(msg.message_id.as_str()).filter(|id| !id.is_empty()))
+        .unwrap_or(msg.id.as_str());
+    get_id(root.as_bytes())[..24].to_string()
+}
+
+/// Orders the messages of a thread depth first, JWZ style: each message hangs under its
+/// closest ancestor present in the thread, siblings are sorted by date. Returns each message
+/// with its depth.
+pub fn sort_thread(msgs: Vec<Message>) -> Vec<(usize, Message)> {
+    let known: HashMap<String, usize> = msgs
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| !m.message_id.is_empty())
+        .map(|(i, m)| (m.message_id.clone(), i))
+        .collect();
+    let mut children: HashMap<Option<usize>, Vec<usize>> = HashMap::new();
+    for (i, msg) in msgs.iter().enumerate() {
+        let parent = ancestors(msg)
+            .into_iter()
+            .rev()
+            .filter_map(|id| known.get(id).copied())
+            .find(|p| *p != i);
+        children.entry(parent).or_default().push(i);
+    }
+    for siblings in children.values_mut() {
+        siblings.sort_by_key(|i| msgs[*i].date);
+    }
+
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    let mut stack = children
+        .get(&None)
+        .map(|roots| roots.iter().rev().map(|r| (0, *r)).collect::<Vec<(usize, usize)>>())
+        .unwrap_or_default();
+    // Messages whose ancestors only form a cycle have no root, append them at the top level.
+    let mut pending = (0..msgs.len()).rev().collect::<Vec<usize>>();
+    loop {
+        let (depth, i) = match stack.pop() {
+            Some(next) => next,
+            None => match pending.pop() {
+                Some(i) => (0, i),
+                None => break,
+            },
+        };
+        if !visited.insert(i) {
+            continue;
+        }
+        order.push((depth, i));
+        if let Some(kids) = children.get(&Some(i)) {
+            stack.extend(kids.iter().rev().map(|k| (depth + 1, *k)));
+        }
+    }
+
+    let mut msgs = msgs.into_iter().map(Some).collect::<Vec<Option<Message>>>();
+    order
+        .into_iter()
+        .filter_map(|(depth, i)| msgs[i].take().map(|m| (depth, m)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_message_ids, root_thread_id, sort_thread};
+    use crate::message::Message;
+
+    fn msg(id: &str, date: u64, refs: &[&str]) -> Message {
+        Message {
+            id: id.to_string(),
+            message_id: format!("{}@example.com", id),
+            references: refs.iter().map(|r| format!("{}@example.com", r)).collect(),
+            date,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_message_id_lists() {
+        assert_eq!(
+            parse_message_ids("<a@b>\r\n <c@d>"),
+            vec!["a@b".to_string(), "c@d".to_string()]
+        );
+        assert_eq!(parse_message_ids("bare@id"), vec!["bare@id".to_string()]);
+    }
+
+    #[test]
+    fn replies_share_the_root_thread_id() {
+        assert_eq!(root_thread_id(&msg("a", 1, &[])), root_thread_id(&msg("b", 2, &["a"])));
+    }
+
+    #[test]
+    fn sorts_replies_under_their_parents() {
+        let msgs = vec![
+            msg("c", 3, &["a", "b"]),
+            msg("d", 4, &["a"]),
+            msg("a", 1, &[]),
+            msg("b", 2, &["a"]),
+            msg("e", 5, &["a", "missing"]),
+        ];
+        let order = sort_thread(msgs)
+            .into_iter()
+            .map(|(depth, m)| (depth, m.id))
+            .collect::<Vec<(usize, String)>>();
+        assert_eq!(
+            order,
+            vec![
+                (0, "a".to_string()),
+                (1, "b".to_string()),
+                (2, "c".to_string()),
+                (1, "d".to_string()),
+                (1, "e".to_string()),
+            ]
+        );
+    }
+}
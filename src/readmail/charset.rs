@@ -0,0 +1,229 @@
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use mailparse::{parse_header, ParsedMail};
+use std::collections::BTreeMap;
+
+fn is_ascii_label(label: &str) -> bool {
+    label.is_empty()
+        || label.eq_ignore_ascii_case("us-ascii")
+        || label.eq_ignore_ascii_case("ascii")
+}
+
+/// Undeclared 8-bit text is UTF-8 more often than not, anything else is read as windows-1252
+/// which maps every byte.
+fn decode_undeclared(bytes: &[u8]) -> (String, bool) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (text.to_string(), true),
+        Err(_) => (
+            WINDOWS_1252
+                .decode_without_bom_handling(bytes)
+                .0
+                .into_owned(),
+            false,
+        ),
+    }
+}
+
+/// Decodes text written in `charset`. Unknown charsets and invalid sequences never fail, the
+/// text is decoded lossily and the returned warning says how.
+pub fn decode_text(bytes: &[u8], charset: &str) -> (String, Option<String>) {
+    let label = charset.trim().trim_matches('"');
+    if is_ascii_label(label) {
+        if bytes.is_ascii() {
+            return (String::from_utf8_lossy(bytes).into_owned(), None);
+        }
+        return match decode_undeclared(bytes) {
+            (text, true) => (text, None),
+            (text, false) => (
+                text,
+                Some("8-bit text without a charset, decoded as windows-1252".to_string()),
+            ),
+        };
+    }
+    match Encoding::for_label(label.as_bytes()) {
+        Some(encoding) => {
+            let (text, used, malformed) = encoding.decode(bytes);
+            let warning = if malformed {
+                Some(format!("invalid {} sequences were replaced", used.name()))
+            } else {
+                None
+            };
+            (text.into_owned(), warning)
+        }
+        None => {
+            let (text, utf8) = decode_undeclared(bytes);
+            let fallback = if utf8 { UTF_8 } else { WINDOWS_1252 };
+            let warning = format!("unknown charset {}, decoded as {}", label, fallback.name());
+            (text, Some(warning))
+        }
+    }
+}
+
+/// Decodes the content of a text part according to its transfer encoding and charset.
+pub fn decode_body(part: &ParsedMail) -> (String, Option<String>) {
+    match part.get_body_raw() {
+        Ok(bytes) => decode_text(&bytes, &part.ctype.charset),
+        Err(e) => (
+            String::new(),
+            Some(format!(
+                "could not decode the {} part: {}",
+                part.ctype.mimetype, e
+            )),
+        ),
+    }
+}
+
+/// Decodes RFC 2047 encoded words (`=?utf-8?q?caf=C3=A9?=`) the same way header values such as
+/// the subject are decoded. Many mailers use them in file names even though RFC 2231 should be
+/// used there.
+pub fn decode_words(value: &str) -> String {
+    if !value.is_ascii() || !value.contains("=?") {
+        return value.to_string();
+    }
+    parse_header(format!("X: {}", value).as_bytes())
+        .map(|(header, _)| header.get_value())
+        .unwrap_or_else(|_| value.to_string())
+}
+
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = input
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Splits the `charset'language'` prefix off an RFC 2231 extended value.
+fn split_charset(value: &str) -> (&str, &str) {
+    let mut parts = value.splitn(3, '\'');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(charset), Some(_), Some(rest)) => (charset, rest),
+        _ => ("", value),
+    }
+}
+
+fn get_ci<'a>(params: &'a BTreeMap<String, String>, key: &str) -> Option<&'a String> {
+    params
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v)
+}
+
+/// The value of the `name` parameter of a `Content-Type` or `Content-Disposition` header.
+/// RFC 2231 continuations (`name*0`, `name*1*`) are joined, RFC 2231 charsets
+/// (`name*=utf-8''%E2%82%AC`) and encoded words are decoded.
+pub fn param(params: &BTreeMap<String, String>, name: &str) -> Option<String> {
+    let extended = format!("{}*", name.to_lowercase());
+    if let Some(value) = get_ci(params, &extended) {
+        let (charset, value) = split_charset(value);
+        return Some(decode_text(&percent_decode(value), charset).0);
+    }
+    let mut segments = params
+        .iter()
+        .filter_map(|(key, value)| {
+            let key = key.to_lowercase();
+            let rest = key.strip_prefix(&extended)?;
+            let (index, encoded) = match rest.strip_suffix('*') {
+                Some(index) => (index, true),
+                None => (rest, false),
+            };
+            index.parse::<usize>().ok().map(|i| (i, encoded, value))
+        })
+        .collect::<Vec<(usize, bool, &String)>>();
+    if segments.is_empty() {
+        return get_ci(params, name).map(|value| decode_words(value));
+    }
+    segments.sort_by_key(|(i, _, _)| *i);
+    let mut charset = "";
+    let mut bytes = vec![];
+    for (i, encoded, value) in segments {
+        if encoded {
+            let value = if i == 0 {
+                let (first_charset, rest) = split_charset(value);
+                charset = first_charset;
+                rest
+            } else {
+                value
+            };
+            bytes.extend(percent_decode(value));
+        } else {
+            bytes.extend(value.as_bytes());
+        }
+    }
+    Some(decode_text(&bytes, charset).0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_text, param};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn decodes_legacy_charsets_lossily() {
+        assert_eq!(
+            decode_text(b"caf\xe9", "windows-1252"),
+            ("café".to_string(), None)
+        );
+        assert_eq!(decode_text(b"\xc4\xe3\xba\xc3", "GB2312").0, "你好");
+        assert_eq!(
+            decode_text(b"\x1b$B$3$s$K$A$O\x1b(B", "iso-2022-jp").0,
+            "こんにちは"
+        );
+        assert_eq!(
+            decode_text("café".as_bytes(), "us-ascii"),
+            ("café".to_string(), None)
+        );
+        let (text, warning) = decode_text(b"caf\xe9", "x-unknown");
+        assert_eq!(text, "café");
+        assert!(warning.unwrap().contains("x-unknown"));
+        let (text, warning) = decode_text(b"caf\xe9", "utf-8");
+        assert_eq!(text, "caf\u{fffd}");
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn decodes_rfc2231_parameters() {
+        let params = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<String, String>>()
+        };
+        assert_eq!(
+            param(
+                &params(&[("filename*", "utf-8''r%C3%A9sum%C3%A9.pdf")]),
+                "filename"
+            ),
+            Some("résumé.pdf".to_string())
+        );
+        assert_eq!(
+            param(
+                &params(&[
+                    ("filename*0*", "iso-8859-1'fr'caf%E9"),
+                    ("filename*1", " menu.txt"),
+                ]),
+                "filename"
+            ),
+            Some("café menu.txt".to_string())
+        );
+        assert_eq!(
+            param(&params(&[("name", "plain.txt")]), "name"),
+            Some("plain.txt".to_string())
+        );
+        assert_eq!(param(&params(&[]), "name"), None);
+    }
+}
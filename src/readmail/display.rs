@@ -1,3 +1,4 @@
+use serde::Deserialize;
 use std::fmt;
 
 impl fmt::Display for OutputTypeError {
@@ -27,6 +28,8 @@ impl std::str::FromStr for OutputType {
             "raw" => Ok(OutputType::Raw),
             "html" => Ok(OutputType::Html),
             "summary" => Ok(OutputType::Summary),
+            "json" => Ok(OutputType::Json),
+            "jsonl" => Ok(OutputType::Jsonl),
             _ => Err(OutputTypeError::UnknownTypeError),
         }
     }
@@ -39,6 +42,8 @@ impl fmt::Display for OutputType{
             OutputType::Html => "Html",
             OutputType::Raw => "Raw",
             OutputType::Short  => "Short",
+            OutputType::Json => "Json",
+            OutputType::Jsonl => "Jsonl",
         };
         write!(f,"{}", msg)
     }
@@ -49,13 +54,18 @@ pub enum OutputTypeError {
     UnknownTypeError,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum OutputType {
     Summary,
     Short,
     Full,
     Raw,
     Html,
+    /// Pretty printed JSON, lists of messages are printed as one array
+    Json,
+    /// One compact JSON object per line
+    Jsonl,
 }
 
 
@@ -1,12 +1,22 @@
 extern crate select;
-use crate::message::{Body, Mime};
+use crate::message::mime::MimePart;
+use crate::message::{Attachment, Body, Mime};
 use log::debug;
 use mailparse::*;
 use select::document::Document;
-use select::predicate::{Text, Name, Predicate};
+use select::predicate::Text;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 
+pub mod charset;
 pub mod display;
+pub mod sanitize;
+pub mod template;
 
 fn cmp_body(x: &Body, y: &Body, prefer: &Mime) -> Ordering {
     if x.mime == y.mime {
@@ -19,59 +29,360 @@ fn cmp_body(x: &Body, y: &Body, prefer: &Mime) -> Ordering {
     }
 }
 
-pub fn extract_body(msg: &ParsedMail, prefer_html: bool) -> Vec<Body> {
+fn decode_part(part: &ParsedMail, warnings: &mut Vec<String>) -> String {
+    let (text, warning) = charset::decode_body(part);
+    if let Some(warning) = warning.filter(|w| !warnings.contains(w)) {
+        warnings.push(warning);
+    }
+    text
+}
+
+fn is_attachment_disposition(part: &ParsedMail) -> bool {
+    part.get_content_disposition().disposition == DispositionType::Attachment
+}
+
+/// Parses the message attached by a `message/rfc822` part, unless the parser already did.
+fn attached_message(part: &ParsedMail) -> Option<Vec<u8>> {
+    if part.ctype.mimetype == "message/rfc822" && part.subparts.is_empty() {
+        part.get_body_raw().ok()
+    } else {
+        None
+    }
+}
+
+/// Appends the decoded `mime` texts of `part` to `texts`, in document order. Containers such
+/// as `multipart/mixed`, `multipart/signed` or attached messages contribute all their texts,
+/// `multipart/alternative` only its last alternative that has one.
+fn collect_texts(
+    part: &ParsedMail,
+    mime: &Mime,
+    warnings: &mut Vec<String>,
+    texts: &mut Vec<String>,
+) {
+    if let Some(data) = attached_message(part) {
+        if let Ok(attached) = parse_mail(&data) {
+            collect_texts(&attached, mime, warnings, texts);
+        }
+    } else if part.ctype.mimetype == "multipart/alternative" {
+        for alternative in part.subparts.iter().rev() {
+            let mut found = vec![];
+            collect_texts(alternative, mime, warnings, &mut found);
+            if !found.is_empty() {
+                texts.extend(found);
+                break;
+            }
+        }
+    } else if part.ctype.mimetype.starts_with("multipart/") {
+        part.subparts
+            .iter()
+            .for_each(|s| collect_texts(s, mime, warnings, texts));
+    } else if part.ctype.mimetype == mime.as_str() && !is_attachment_disposition(part) {
+        texts.push(decode_part(part, warnings));
+    }
+}
+
+/// Collects the text bodies of a message, decoded from their charsets: one plain text and one
+/// html body joining the texts of every part, at least an empty plain text one. Parts that
+/// could only be decoded lossily add a line to `warnings`.
+pub fn extract_body(msg: &ParsedMail, prefer_html: bool, warnings: &mut Vec<String>) -> Vec<Body> {
     let prefered_mime = if prefer_html {
         Mime::Html
     } else {
         Mime::PlainText
     };
-    let text = msg.get_body().unwrap_or_else(|_| {
-        msg.get_body_raw().map_or(String::from(""), |x| {
-            String::from_utf8(x).unwrap_or_else(|_| String::new())
+    let mut bodies = vec![Mime::PlainText, Mime::Html]
+        .into_iter()
+        .filter_map(|mime| {
+            let mut texts = vec![];
+            collect_texts(msg, &mime, warnings, &mut texts);
+            if texts.is_empty() {
+                None
+            } else {
+                Some(Body::new(mime, texts.join("\n\n")))
+            }
         })
-    });
-    let mime = msg.ctype.mimetype.parse::<Mime>().unwrap();
-    let raw_body = Some(Body::new(mime, text));
+        .collect::<Vec<Body>>();
+    if bodies.is_empty() {
+        debug!("No text body in a {} message", msg.ctype.mimetype);
+        bodies.push(Body::new(Mime::PlainText, String::new()));
+    }
+    bodies.sort_unstable_by(|x, y| cmp_body(x, y, &prefered_mime));
+    bodies
+}
 
-    let mut bodies = msg
+fn part_size(part: &ParsedMail) -> usize {
+    part.get_body_raw().map(|b| b.len()).unwrap_or(0)
+}
+
+/// The MIME structure of a message, attached messages included.
+pub fn mime_tree(part: &ParsedMail) -> MimePart {
+    let disposition = part.get_content_disposition();
+    let mut parts = part
         .subparts
         .iter()
-        .map(|s| {
-            let mime = s.ctype.mimetype.parse::<Mime>().unwrap();
-            match mime {
-                Mime::PlainText | Mime::Html => s.get_body().ok().map(|b| Body::new(mime, b)),
-                Mime::Nested => extract_body(&s, prefer_html).into_iter().next(),
-                Mime::Unknown => {
-                    debug!("unknown mime {}", mime.as_str());
-                    None
-                }
-            }
+        .map(mime_tree)
+        .collect::<Vec<MimePart>>();
+    if let Some(data) = attached_message(part) {
+        if let Ok(attached) = parse_mail(&data) {
+            parts.push(mime_tree(&attached));
+        }
+    }
+    let size = if part.ctype.mimetype.starts_with("multipart/") {
+        parts.iter().map(|p| p.size).sum()
+    } else {
+        part_size(part)
+    };
+    let params = part
+        .ctype
+        .params
+        .keys()
+        .map(|key| key.split('*').next().unwrap_or(key))
+        .filter_map(|name| {
+            charset::param(&part.ctype.params, name).map(|value| (name.to_string(), value))
         })
-        .filter_map(|s| s)
-        .collect::<Vec<Body>>();
-    if raw_body.is_some() {
-        bodies.push(raw_body.expect("COULD NOT UNWRAP RAW_BODY"));
+        .collect();
+    MimePart {
+        content_type: part.ctype.mimetype.clone(),
+        params,
+        disposition: match disposition.disposition {
+            DispositionType::Inline => Some("inline".to_string()),
+            DispositionType::Attachment => Some("attachment".to_string()),
+            _ => None,
+        }
+        .filter(|_| {
+            part.headers
+                .get_first_value("Content-Disposition")
+                .is_some()
+        }),
+        filename: charset::param(&disposition.params, "filename")
+            .or_else(|| charset::param(&part.ctype.params, "name")),
+        content_id: content_id(part),
+        size,
+        parts,
     }
-    bodies.sort_unstable_by(|x, y| cmp_body(x, y, &prefered_mime));
-    if bodies.is_empty() {
-        println!(
-            "No body for message: {}",
-            msg.headers
-                .iter()
-                .map(|x| format!("{}:{}", x.get_key(), x.get_value()))
-                .collect::<Vec<String>>()
-                .join("\n")
-        );
+}
+
+/// The decoded content of the part at `path`, a list of child indices as in
+/// [`MimePart::numbered`].
+pub fn part_content(part: &ParsedMail, path: &[usize]) -> Option<Vec<u8>> {
+    match path.split_first() {
+        None => part.get_body_raw().ok(),
+        Some((&i, rest)) => match attached_message(part) {
+            Some(data) if i == 0 => part_content(&parse_mail(&data).ok()?, rest),
+            Some(_) => None,
+            None => part_content(part.subparts.get(i)?, rest),
+        },
     }
-    bodies
 }
 
-pub fn html2text(text: &str) -> String {
-    let document = Document::from(text);
-    let body = document.find(Name("body")).nth(0).unwrap();
-    let text_nodes = body.find(Text)
-        .map(|x| String::from(x.text().trim()))
-        .filter(|x| x.len() > 1)
-        .collect::<Vec<String>>();
-    text_nodes.join("\n\n")
+fn is_attachment(part: &ParsedMail) -> bool {
+    if !part.subparts.is_empty() {
+        return false;
+    }
+    let disposition = part.get_content_disposition();
+    disposition.disposition == DispositionType::Attachment
+        || charset::param(&disposition.params, "filename").is_some()
+        || charset::param(&part.ctype.params, "name").is_some()
+        || !(part.ctype.mimetype.starts_with("text/")
+            || part.ctype.mimetype.starts_with("multipart/"))
+}
+
+fn collect_attachment_parts<'a, 'b>(msg: &'b ParsedMail<'a>, parts: &mut Vec<&'b ParsedMail<'a>>) {
+    if is_attachment(msg) {
+        parts.push(msg);
+    }
+    msg.subparts
+        .iter()
+        .for_each(|s| collect_attachment_parts(s, parts));
+}
+
+fn attachment_parts<'a, 'b>(msg: &'b ParsedMail<'a>) -> Vec<&'b ParsedMail<'a>> {
+    let mut parts = vec![];
+    // A single part message only has an attachment if it says so explicitly.
+    if msg.subparts.is_empty() {
+        if msg.get_content_disposition().disposition == DispositionType::Attachment {
+            parts.push(msg);
+        }
+    } else {
+        collect_attachment_parts(msg, &mut parts);
+    }
+    parts
+}
+
+fn to_attachment(part: &ParsedMail, index: usize) -> Attachment {
+    let filename = charset::param(&part.get_content_disposition().params, "filename")
+        .or_else(|| charset::param(&part.ctype.params, "name"))
+        .unwrap_or_else(|| format!("attachment-{}", index + 1));
+    Attachment {
+        filename,
+        content_type: part.ctype.mimetype.clone(),
+        size: part.get_body_raw().map(|b| b.len()).unwrap_or(0),
+        content_id: content_id(part),
+    }
+}
+
+fn content_id(part: &ParsedMail) -> Option<String> {
+    part.headers.get_first_value("Content-ID").map(|id| {
+        id.trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_string()
+    })
+}
+
+pub fn extract_attachments(msg: &ParsedMail) -> Vec<Attachment> {
+    attachment_parts(msg)
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| to_attachment(part, i))
+        .collect()
+}
+
+fn safe_filename(filename: &str) -> String {
+    let name = filename.replace(&['/', '\\'][..], "_");
+    match name.trim() {
+        "" | "." | ".." => "attachment".to_string(),
+        n => n.to_string(),
+    }
+}
+
+fn unused_path(dir: &Path, filename: &str) -> PathBuf {
+    let candidate = dir.join(filename);
+    if !candidate.exists() {
+        return candidate;
+    }
+    let (stem, ext) = match filename.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (filename, String::new()),
+    };
+    (1..)
+        .map(|i| dir.join(format!("{}-{}{}", stem, i, ext)))
+        .find(|p| !p.exists())
+        .expect("Ran out of attachment file names")
+}
+
+/// Writes the attachments of `msg` into `dir`, only those named `name` if given.
+/// Returns the paths written to.
+pub fn save_attachments(
+    msg: &ParsedMail,
+    name: Option<&str>,
+    dir: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let mut saved = vec![];
+    for (i, part) in attachment_parts(msg).into_iter().enumerate() {
+        let attachment = to_attachment(part, i);
+        if name.map_or(false, |n| n != attachment.filename) {
+            continue;
+        }
+        let data = part
+            .get_body_raw()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let path = unused_path(dir, &safe_filename(&attachment.filename));
+        fs::write(&path, data)?;
+        saved.push(path);
+    }
+    Ok(saved)
+}
+
+fn url_encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Writes the parts of `msg` that have a content id into `dir`, such as the images shown by
+/// its html body. Returns the URLs of the files written, relative to `dir`, by content id.
+pub fn save_inline_parts(
+    msg: &ParsedMail,
+    tree: &MimePart,
+    dir: &Path,
+) -> io::Result<HashMap<String, String>> {
+    fs::create_dir_all(dir)?;
+    let mut saved = HashMap::new();
+    for (i, numbered) in tree.numbered().into_iter().enumerate() {
+        let part = numbered.part;
+        let id = match part.content_id.as_ref() {
+            Some(id) if part.parts.is_empty() => id,
+            _ => continue,
+        };
+        let data = match part_content(msg, &numbered.path) {
+            Some(data) => data,
+            None => continue,
+        };
+        let filename = part
+            .filename
+            .clone()
+            .unwrap_or_else(|| format!("inline-{}", i + 1));
+        let path = unused_path(dir, &safe_filename(&filename));
+        fs::write(&path, data)?;
+        if let Some(name) = path.file_name() {
+            saved.insert(id.clone(), url_encode(&name.to_string_lossy()));
+        }
+    }
+    Ok(saved)
+}
+
+pub const DEFAULT_HTML_WIDTH: usize = 80;
+
+static HTML_WIDTH: AtomicUsize = AtomicUsize::new(DEFAULT_HTML_WIDTH);
+
+/// Sets the width html bodies are wrapped to by [`html2text`].
+pub fn set_html_width(width: usize) {
+    HTML_WIDTH.store(width.max(1), AtomicOrdering::Relaxed);
+}
+
+/// The text nodes of a document or fragment, one paragraph each. Used when the renderer
+/// fails.
+fn text_nodes(html: &str) -> String {
+    let document = Document::from(html);
+    document
+        .find(Text)
+        .filter(|node| {
+            !node
+                .parent()
+                .and_then(|parent| parent.name())
+                .map_or(false, |name| name == "script" || name == "style")
+        })
+        .map(|node| node.text().trim().to_string())
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+/// Renders html as text wrapped to the configured width. Paragraphs, lists, tables and nested
+/// quotes keep their layout, links are numbered with their targets listed at the end.
+pub fn html2text(html: &str) -> String {
+    let width = HTML_WIDTH.load(AtomicOrdering::Relaxed);
+    // The renderer has been known to panic on odd markup, a message must still be readable.
+    panic::catch_unwind(|| ::html2text::from_read(html.as_bytes(), width))
+        .unwrap_or_else(|_| text_nodes(html))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{html2text, text_nodes};
+
+    #[test]
+    fn renders_html_fragments() {
+        let text = html2text(
+            "<p>Hello <a href=\"https://example.com/\">there</a></p>\
+             <ul><li>one</li><li>two</li></ul><blockquote>quoted</blockquote>",
+        );
+        assert!(text.contains("Hello"));
+        assert!(text.contains("https://example.com/"));
+        assert!(text.contains("one") && text.contains("two"));
+        assert!(text.contains("> quoted"));
+        html2text("");
+        html2text("<td>unclosed<table><tr>");
+        assert_eq!(
+            text_nodes("<style>p {}</style><p>one</p> <b>two</b>"),
+            "one\n\ntwo"
+        );
+    }
 }
@@ -0,0 +1,327 @@
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::states::RawKind;
+use html5ever::tokenizer::{
+    BufferQueue, Doctype, Tag, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+};
+use std::collections::HashMap;
+
+/// Elements dropped along with everything they contain.
+const DROPPED_WITH_CONTENT: &[&str] = &["script", "iframe", "noembed", "noframes"];
+
+/// Elements dropped while their content is kept, such as the fallback content of `object`.
+const DROPPED: &[&str] = &[
+    "base", "link", "meta", "embed", "object", "applet", "frame", "frameset",
+];
+
+/// Attributes holding a URL.
+const URL_ATTRIBUTES: &[&str] = &[
+    "href",
+    "src",
+    "background",
+    "poster",
+    "lowsrc",
+    "dynsrc",
+    "data",
+    "cite",
+    "longdesc",
+    "icon",
+    "manifest",
+    "xlink:href",
+];
+
+/// Attributes dropped whatever their value: event handlers and where forms are sent.
+fn is_dropped_attribute(name: &str) -> bool {
+    name.starts_with("on") || ["srcset", "action", "formaction", "ping"].contains(&name)
+}
+
+fn raw_kind(name: &str) -> Option<RawKind> {
+    match name {
+        "script" => Some(RawKind::ScriptData),
+        "style" | "iframe" | "noembed" | "noframes" | "xmp" => Some(RawKind::Rawtext),
+        "title" | "textarea" => Some(RawKind::Rcdata),
+        _ => None,
+    }
+}
+
+fn escape(text: &str, attribute: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' if attribute => escaped.push_str("&quot;"),
+            '<' if !attribute => escaped.push_str("&lt;"),
+            '>' if !attribute => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn is_remote(url: &str) -> bool {
+    ["http:", "https:", "ftp:", "//"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+}
+
+/// Replaces the case insensitive occurrences of `from`, which must be lowercase ASCII.
+fn replace_ignore_case(text: &str, from: &str, to: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, _) in lower.match_indices(from) {
+        replaced.push_str(&text[last..start]);
+        replaced.push_str(to);
+        last = start + from.len();
+    }
+    replaced.push_str(&text[last..]);
+    replaced
+}
+
+struct Sanitizer<'a> {
+    out: String,
+    remote: bool,
+    inline: &'a HashMap<String, String>,
+    /// Name of the element whose content is being dropped.
+    skipping: Option<String>,
+    /// Content of the `style` element being read.
+    style: Option<String>,
+}
+
+impl<'a> Sanitizer<'a> {
+    /// The value to keep for a URL, if any. `cid:` URLs are replaced by the inline part they
+    /// refer to, remote resources are dropped unless allowed. Links are kept since they are
+    /// only followed when clicked.
+    fn url(&self, attribute: &str, value: &str) -> Option<String> {
+        let url = value
+            .chars()
+            .filter(|c| !c.is_ascii_whitespace() && !c.is_control())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if url.starts_with("javascript:")
+            || url.starts_with("vbscript:")
+            || url.starts_with("data:text/html")
+        {
+            return None;
+        }
+        let value = value.trim();
+        if let Some(id) = value
+            .get(..4)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("cid:"))
+            .map(|_| &value[4..])
+        {
+            let id = id.trim_start_matches('<').trim_end_matches('>');
+            return self.inline.get(id).cloned();
+        }
+        if attribute == "href" || self.remote || !is_remote(&url) {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn css(&self, css: &str) -> String {
+        let lower = css.to_ascii_lowercase();
+        let mut sanitized = String::with_capacity(css.len());
+        let mut last = 0;
+        while let Some(found) = lower[last..].find("url(") {
+            let start = last + found;
+            let args = start + 4;
+            let end = lower[args..]
+                .find(')')
+                .map_or(lower.len(), |end| args + end);
+            let url = css[args..end]
+                .trim()
+                .trim_matches(|c| c == '"' || c == '\'');
+            sanitized.push_str(&css[last..start]);
+            match self.url("src", url) {
+                Some(url) => sanitized.push_str(&format!("url(\"{}\")", url.replace('"', "%22"))),
+                None => sanitized.push_str("url()"),
+            }
+            last = (end + 1).min(css.len());
+        }
+        sanitized.push_str(&css[last..]);
+        if self.remote {
+            sanitized
+        } else {
+            replace_ignore_case(&sanitized, "@import", "@x-blocked-import")
+        }
+    }
+
+    fn doctype(&mut self, doctype: Doctype) {
+        if let Some(name) = doctype.name {
+            self.out.push_str(&format!("<!DOCTYPE {}", name));
+            match (doctype.public_id, doctype.system_id) {
+                (Some(public), system) => {
+                    self.out
+                        .push_str(&format!(" PUBLIC \"{}\"", escape(&public, true)));
+                    if let Some(system) = system {
+                        self.out
+                            .push_str(&format!(" \"{}\"", escape(&system, true)));
+                    }
+                }
+                (None, Some(system)) => {
+                    self.out
+                        .push_str(&format!(" SYSTEM \"{}\"", escape(&system, true)));
+                }
+                (None, None) => {}
+            }
+            self.out.push('>');
+        }
+    }
+
+    fn tag(&mut self, tag: Tag) -> TokenSinkResult<()> {
+        let name = tag.name.to_string();
+        let raw = match (tag.kind, tag.self_closing) {
+            (TagKind::StartTag, false) => raw_kind(&name),
+            _ => None,
+        };
+        let result = raw.map_or(TokenSinkResult::Continue, TokenSinkResult::RawData);
+        if let Some(skipped) = self.skipping.as_ref() {
+            if tag.kind == TagKind::EndTag && name == *skipped {
+                self.skipping = None;
+            }
+            return TokenSinkResult::Continue;
+        }
+        if DROPPED_WITH_CONTENT.contains(&name.as_str()) {
+            if raw.is_some() {
+                self.skipping = Some(name);
+            }
+            return result;
+        }
+        if DROPPED.contains(&name.as_str()) {
+            return result;
+        }
+        match tag.kind {
+            TagKind::EndTag => {
+                if let Some(css) = self.style.take() {
+                    let css = self.css(&css);
+                    self.out.push_str(&css);
+                }
+                self.out.push_str(&format!("</{}>", name));
+            }
+            TagKind::StartTag => {
+                self.out.push('<');
+                self.out.push_str(&name);
+                for attr in tag.attrs.iter() {
+                    let attr_name = attr.name.local.to_ascii_lowercase();
+                    let value = if is_dropped_attribute(&attr_name) {
+                        None
+                    } else if attr_name == "style" {
+                        Some(self.css(&attr.value))
+                    } else if URL_ATTRIBUTES.contains(&attr_name.as_str()) {
+                        self.url(&attr_name, &attr.value)
+                    } else {
+                        Some(attr.value.to_string())
+                    };
+                    if let Some(value) = value {
+                        self.out
+                            .push_str(&format!(" {}=\"{}\"", attr_name, escape(&value, true)));
+                    }
+                }
+                self.out.push_str(if tag.self_closing { "/>" } else { ">" });
+                if name == "style" && raw.is_some() {
+                    self.style = Some(String::new());
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<'a> TokenSink for Sanitizer<'a> {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::TagToken(tag) => return self.tag(tag),
+            Token::CharacterTokens(text) if self.skipping.is_none() => match self.style.as_mut() {
+                Some(css) => css.push_str(&text),
+                None => self.out.push_str(&escape(&text, false)),
+            },
+            Token::DoctypeToken(doctype) => self.doctype(doctype),
+            Token::EOFToken => {
+                if let Some(css) = self.style.take() {
+                    let css = self.css(&css);
+                    self.out.push_str(&css);
+                }
+            }
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+/// Makes an html part safe to open in a browser. Scripts, frames, event handlers and forms
+/// are dropped. Remote resources such as tracking images are dropped as well unless `remote`
+/// is set, and a Content-Security-Policy keeps the browser from loading anything missed.
+/// `cid:` URLs are replaced by the URLs of `inline`, keyed by content id.
+pub fn sanitize(html: &str, remote: bool, inline: &HashMap<String, String>) -> String {
+    let sanitizer = Sanitizer {
+        out: String::with_capacity(html.len()),
+        remote,
+        inline,
+        skipping: None,
+        style: None,
+    };
+    let mut tokenizer = Tokenizer::new(sanitizer, TokenizerOpts::default());
+    let mut input = BufferQueue::new();
+    input.push_back(StrTendril::from_slice(html));
+    let _ = tokenizer.feed(&mut input);
+    tokenizer.end();
+    let mut out = tokenizer.sink.out;
+
+    let sources = if remote {
+        "* data: file:"
+    } else {
+        "data: file:"
+    };
+    let head = format!(
+        "<meta charset=\"utf-8\">\n<meta http-equiv=\"Content-Security-Policy\" \
+         content=\"default-src 'none'; img-src {0}; media-src {0}; font-src {0}; \
+         style-src 'unsafe-inline' {0}\">\n",
+        sources
+    );
+    // The policy has to come after the doctype for the page not to be rendered in quirks mode.
+    let at = if out.starts_with("<!DOCTYPE") {
+        out.find('>').map_or(0, |end| end + 1)
+    } else {
+        0
+    };
+    out.insert_str(at, &head);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::sanitize;
+    use std::collections::HashMap;
+
+    #[test]
+    fn strips_scripts_and_remote_content() {
+        let inline = vec![("logo@example.com".to_string(), "logo.png".to_string())]
+            .into_iter()
+            .collect::<HashMap<String, String>>();
+        let html = sanitize(
+            "<!DOCTYPE html><html><head><script>alert('</p>')</script>\
+             <style>body { background: url('https://t.example.com/bg.png') }</style></head>\
+             <body onload=\"track()\"><p style=\"color: red\">Hi &amp; bye</p>\
+             <img src=\"https://t.example.com/pixel.gif\"><img src=\"cid:logo@example.com\">\
+             <a href=\"https://example.com/\">link</a><a href=\"javascript:steal()\">x</a>\
+             <iframe src=\"https://example.com/\">frame</iframe></body></html>",
+            false,
+            &inline,
+        );
+        assert!(html.starts_with("<!DOCTYPE html><meta charset=\"utf-8\">"));
+        assert!(html.contains("Content-Security-Policy"));
+        assert!(!html.contains("alert") && !html.contains("track()"));
+        assert!(!html.contains("t.example.com"));
+        assert!(html.contains("background: url()"));
+        assert!(html.contains("<p style=\"color: red\">Hi &amp; bye</p>"));
+        assert!(html.contains("<img><img src=\"logo.png\">"));
+        assert!(html.contains("<a href=\"https://example.com/\">link</a><a>x</a>"));
+        assert!(!html.contains("frame"));
+
+        let html = sanitize("<img src=\"https://example.com/a.png\">", true, &inline);
+        assert!(html.ends_with("<img src=\"https://example.com/a.png\">"));
+    }
+}
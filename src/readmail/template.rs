@@ -0,0 +1,255 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::prelude::*;
+use std::fmt;
+
+pub const DEFAULT_DATE_FORMAT: &str = "%a %b %e %T %Y";
+
+/// Fields a template can refer to. Items that lack a field render it empty.
+pub const FIELDS: &[&str] = &[
+    "id",
+    "short_id",
+    "thread",
+    "date",
+    "from",
+    "from.name",
+    "from.email",
+    "to",
+    "cc",
+    "subject",
+    "tags",
+    "maildir",
+    "folder",
+    "excerpt",
+    "body",
+    "path",
+    "attachments",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct TemplateError(pub String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid template: {}", self.0)
+    }
+}
+impl std::error::Error for TemplateError {}
+
+pub enum FieldValue {
+    Text(String),
+    /// Seconds since the epoch, rendered in local time.
+    Date(u64),
+}
+
+pub trait TemplateFields {
+    fn field(&self, name: &str) -> Option<FieldValue>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Spec {
+    align: Align,
+    width: Option<usize>,
+    max: Option<usize>,
+    date_format: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field { name: String, spec: Spec },
+}
+
+/// An output layout such as `{date:%Y-%m-%d} {from.name:<20.20} {subject} [{tags}]`.
+///
+/// Fields are written `{name}` or `{name:spec}`. The spec of `date` is a strftime format, the
+/// spec of other fields is an optional alignment (`<`, `>` or `^`), a minimum width and a
+/// maximum length after a dot, as in `{subject:>30.40}`. `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+fn parse_number(input: &str) -> Result<Option<usize>, TemplateError> {
+    if input.is_empty() {
+        return Ok(None);
+    }
+    input
+        .parse::<usize>()
+        .map(Some)
+        .map_err(|_| TemplateError(format!("invalid width {}", input)))
+}
+
+fn parse_spec(name: &str, spec: &str) -> Result<Spec, TemplateError> {
+    let mut parsed = Spec {
+        align: Align::Left,
+        width: None,
+        max: None,
+        date_format: None,
+    };
+    if name == "date" {
+        if !spec.is_empty() {
+            if StrftimeItems::new(spec).any(|i| matches!(i, Item::Error)) {
+                return Err(TemplateError(format!("invalid date format {}", spec)));
+            }
+            parsed.date_format = Some(spec.to_string());
+        }
+        return Ok(parsed);
+    }
+    let rest = match spec.chars().next() {
+        Some('<') => &spec[1..],
+        Some('>') => {
+            parsed.align = Align::Right;
+            &spec[1..]
+        }
+        Some('^') => {
+            parsed.align = Align::Center;
+            &spec[1..]
+        }
+        _ => spec,
+    };
+    let (width, max) = match rest.split_once('.') {
+        Some((width, max)) => (width, parse_number(max)?),
+        None => (rest, None),
+    };
+    parsed.width = parse_number(width)?;
+    parsed.max = max;
+    Ok(parsed)
+}
+
+fn parse_field(content: &str) -> Result<Part, TemplateError> {
+    let (name, spec) = content.split_once(':').unwrap_or((content, ""));
+    let name = name.trim();
+    if !FIELDS.contains(&name) {
+        return Err(TemplateError(format!("unknown field {}", name)));
+    }
+    Ok(Part::Field {
+        name: name.to_string(),
+        spec: parse_spec(name, spec)?,
+    })
+}
+
+impl Template {
+    pub fn parse(input: &str) -> Result<Template, TemplateError> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = input.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut content = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => content.push(c),
+                            None => return Err(TemplateError(format!("unclosed {{{}", content))),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(parse_field(&content)?);
+                }
+                '}' => return Err(TemplateError("unmatched }, use }} for a brace".to_string())),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn render<T: TemplateFields>(&self, item: &T) -> String {
+        let mut out = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Field { name, spec } => {
+                    let value = match item.field(name) {
+                        Some(FieldValue::Text(text)) => text,
+                        Some(FieldValue::Date(date)) => Local
+                            .timestamp(date as i64, 0)
+                            .format(spec.date_format.as_deref().unwrap_or(DEFAULT_DATE_FORMAT))
+                            .to_string(),
+                        None => String::new(),
+                    };
+                    out.push_str(&apply_spec(value, spec));
+                }
+            }
+        }
+        out
+    }
+}
+
+fn apply_spec(value: String, spec: &Spec) -> String {
+    let mut value = match spec.max {
+        Some(max) => value.chars().take(max).collect(),
+        None => value,
+    };
+    let len = value.chars().count();
+    if let Some(width) = spec.width.filter(|w| *w > len) {
+        let pad = width - len;
+        let (left, right) = match spec.align {
+            Align::Left => (0, pad),
+            Align::Right => (pad, 0),
+            Align::Center => (pad / 2, pad - pad / 2),
+        };
+        value = format!("{}{}{}", " ".repeat(left), value, " ".repeat(right));
+    }
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FieldValue, Template, TemplateFields};
+    use chrono::prelude::*;
+
+    struct Item;
+
+    impl TemplateFields for Item {
+        fn field(&self, name: &str) -> Option<FieldValue> {
+            match name {
+                "subject" => Some(FieldValue::Text("Quarterly report".to_string())),
+                "from.name" => Some(FieldValue::Text("Alice".to_string())),
+                "tags" => Some(FieldValue::Text("inbox,work".to_string())),
+                "date" => Some(FieldValue::Date(
+                    Local.ymd(2024, 3, 14).and_hms(9, 30, 0).timestamp() as u64,
+                )),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn renders_fields_dates_and_widths() {
+        let template = Template::parse("{date:%Y-%m-%d} {from.name:>7} {subject:.9}| [{tags}]{to}")
+            .unwrap();
+        assert_eq!(template.render(&Item), "2024-03-14   Alice Quarterly| [inbox,work]");
+        let template = Template::parse("{{{subject:^20.9}}}").unwrap();
+        assert_eq!(template.render(&Item), "{     Quarterly      }");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(Template::parse("{nope}").is_err());
+        assert!(Template::parse("{subject").is_err());
+        assert!(Template::parse("subject}").is_err());
+        assert!(Template::parse("{subject:x}").is_err());
+        assert!(Template::parse("{date:%Q}").is_err());
+    }
+}
@@ -1,12 +1,20 @@
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 
 use kv::*;
 
-use crate::message::Message;
+use crate::message::{thread, Message};
+use crate::stores::address_book::{participants, Correspondent};
+use crate::stores::kv::{IndexedPath, Kv as _};
 use crate::stores::MessageStoreError;
 
 pub struct Kv<'a> {
     msg_by_id: Bucket<'a, String, Json<Message>>,
+    ids_by_tag: Bucket<'a, String, Json<HashSet<String>>>,
+    ids_by_thread: Bucket<'a, String, Json<HashSet<String>>>,
+    thread_by_message_id: Bucket<'a, String, String>,
+    by_path: Bucket<'a, String, Json<IndexedPath>>,
+    correspondents: Bucket<'a, String, Json<Correspondent>>,
 }
 
 impl<'a> Kv<'a> {
@@ -14,29 +22,218 @@ impl<'a> Kv<'a> {
         let cfg = Config::new(path);
         let store = Store::new(cfg)?;
         let msg_by_id = store.bucket::<String, Json<Message>>(Some("by_id"))?;
-        Ok(Kv { msg_by_id })
+        let ids_by_tag = store.bucket::<String, Json<HashSet<String>>>(Some("ids_by_tag"))?;
+        let ids_by_thread =
+            store.bucket::<String, Json<HashSet<String>>>(Some("ids_by_thread"))?;
+        let thread_by_message_id =
+            store.bucket::<String, String>(Some("thread_by_message_id"))?;
+        let by_path = store.bucket::<String, Json<IndexedPath>>(Some("by_path"))?;
+        let correspondents =
+            store.bucket::<String, Json<Correspondent>>(Some("correspondents"))?;
+        Ok(Kv {
+            msg_by_id,
+            ids_by_tag,
+            ids_by_thread,
+            thread_by_message_id,
+            by_path,
+            correspondents,
+        })
+    }
+
+    fn update_id_sets(
+        bucket: &Bucket<'a, String, Json<HashSet<String>>>,
+        id: &str,
+        added: &HashSet<String>,
+        removed: &HashSet<String>,
+    ) -> Result<(), Error> {
+        for key in added.union(removed) {
+            let mut ids = bucket
+                .get(key)?
+                .map(|json_ids| json_ids.0)
+                .unwrap_or_default();
+            if added.contains(key) {
+                ids.insert(id.to_string());
+            } else {
+                ids.remove(id);
+            }
+            if ids.is_empty() {
+                bucket.remove(key)?;
+            } else {
+                bucket.set(key, &Json(ids))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `id` from the set at `key` within a transaction, dropping emptied sets.
+    fn remove_from_id_set(
+        txn: &Transaction<String, Json<HashSet<String>>>,
+        key: &str,
+        id: &str,
+    ) -> Result<(), TransactionError<Error>> {
+        let key = key.to_string();
+        if let Some(Json(mut ids)) = txn.get(&key)? {
+            ids.remove(id);
+            if ids.is_empty() {
+                txn.remove(&key)?;
+            } else {
+                txn.set(&key, &Json(ids))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_id_set(
+        bucket: &Bucket<'a, String, Json<HashSet<String>>>,
+        key: &str,
+    ) -> Result<HashSet<String>, Error> {
+        Ok(bucket
+            .get(&key.to_string())?
+            .map(|json_ids| json_ids.0)
+            .unwrap_or_default())
+    }
+
+    fn update_tag_index(
+        &self,
+        id: &str,
+        added: &HashSet<String>,
+        removed: &HashSet<String>,
+    ) -> Result<(), MessageStoreError> {
+        Self::update_id_sets(&self.ids_by_tag, id, added, removed).map_err(|e| {
+            MessageStoreError::CouldNotModifyMessage(format!(
+                "Unable to update the tags of {} in the KV store: {}",
+                id, e
+            ))
+        })
+    }
+
+    fn update_thread_index(&self, msg: &Message) -> Result<(), MessageStoreError> {
+        if msg.thread.is_empty() {
+            return Ok(());
+        }
+        let err = |e: Error| {
+            MessageStoreError::CouldNotModifyMessage(format!(
+                "Unable to update the thread of {} in the KV store: {}",
+                msg.id, e
+            ))
+        };
+        let threads = vec![msg.thread.clone()].into_iter().collect::<HashSet<String>>();
+        Self::update_id_sets(&self.ids_by_thread, &msg.id, &threads, &HashSet::new())
+            .map_err(err)?;
+        let related = thread::ancestors(msg)
+            .into_iter()
+            .chain(Some(&msg.message_id).filter(|id| !id.is_empty()));
+        for message_id in related {
+            if !self.thread_by_message_id.contains(message_id).map_err(err)? {
+                self.thread_by_message_id
+                    .set(message_id, &msg.thread)
+                    .map_err(err)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the correspondents of a message in the address book, or discounts them when
+    /// `add` is false.
+    fn update_address_book(&self, msg: &Message, add: bool) -> Result<(), MessageStoreError> {
+        let err = |e: Error| {
+            MessageStoreError::CouldNotModifyMessage(format!(
+                "Unable to update the address book with {}: {}",
+                msg.id, e
+            ))
+        };
+        for (address, sent) in participants(msg) {
+            let key = Correspondent::key(&address.email);
+            let mut correspondent = self
+                .correspondents
+                .get(&key)
+                .map_err(err)?
+                .map(|json| json.0)
+                .unwrap_or_else(|| Correspondent::new(&address.email));
+            if add {
+                correspondent.record(address, sent, msg.date);
+            } else {
+                correspondent.forget(sent);
+            }
+            if correspondent.is_unused() {
+                self.correspondents.remove(&key).map_err(err)?;
+            } else {
+                self.correspondents.set(&key, &Json(correspondent)).map_err(err)?;
+            }
+        }
+        Ok(())
     }
 }
+
 impl<'a> crate::stores::Store for Kv<'a> {
     fn add_message(&mut self, msg: Message) -> Result<Message, MessageStoreError> {
+        let previous = self.get_message(&msg.id)?;
+        if previous.is_none() {
+            self.update_address_book(&msg, true)?;
+        }
+        let previous_tags = previous.map(|m| m.tags).unwrap_or_default();
         self.msg_by_id
             .set(&msg.id, &Json(msg.clone()))
-            .map(|_| msg)
             .map_err(|e| {
                 MessageStoreError::CouldNotAddMessage(format!(
                     "Unable to add the message to the KV store: {}",
                     e
                 ))
-            })
+            })?;
+        let added = msg.tags.difference(&previous_tags).cloned().collect();
+        let removed = previous_tags.difference(&msg.tags).cloned().collect();
+        self.update_tag_index(&msg.id, &added, &removed)?;
+        self.update_thread_index(&msg)?;
+        if let Some(path) = msg.path.as_ref() {
+            let indexed = IndexedPath {
+                id: msg.id.clone(),
+                mtime: msg.mtime,
+            };
+            self.by_path
+                .set(&path.to_string_lossy().into_owned(), &Json(indexed))
+                .map_err(|e| {
+                    MessageStoreError::CouldNotAddMessage(format!(
+                        "Unable to record the path of {} in the KV store: {}",
+                        msg.id, e
+                    ))
+                })?;
+        }
+        Ok(msg)
     }
 
     fn delete_message(&mut self, msg: &Message) -> Result<(), crate::stores::MessageStoreError> {
-        self.msg_by_id.remove(&msg.id).map_err(|e| {
-            MessageStoreError::CouldNotDeleteMessage(format!(
-                "Unable to delete the message to the KV store: {}",
-                e
-            ))
-        })
+        let previous = self.get_message(&msg.id)?;
+        let stored = previous.clone().unwrap_or_else(|| msg.clone());
+        // The message and the tag and thread sets pointing to it go away together. The paths
+        // and the address book follow, both can be rebuilt from the messages.
+        self.msg_by_id
+            .transaction3(
+                &self.ids_by_tag,
+                &self.ids_by_thread,
+                |by_id, by_tag, by_thread| {
+                    by_id.remove(&stored.id)?;
+                    for tag in stored.tags.iter() {
+                        Self::remove_from_id_set(&by_tag, tag, &stored.id)?;
+                    }
+                    if !stored.thread.is_empty() {
+                        Self::remove_from_id_set(&by_thread, &stored.thread, &stored.id)?;
+                    }
+                    Ok(())
+                },
+            )
+            .map_err(|e| {
+                MessageStoreError::CouldNotDeleteMessage(format!(
+                    "Unable to delete {} from the KV store: {}",
+                    msg.id, e
+                ))
+            })?;
+        if let Some(path) = stored.path.as_ref() {
+            self.remove_indexed_path(path)?;
+        }
+        match previous {
+            Some(previous) => self.update_address_book(&previous, false),
+            None => Ok(()),
+        }
     }
 
     fn update_message(
@@ -85,27 +282,226 @@ impl<'a> crate::stores::kv::Kv for Kv<'a> {
             result
     }
 
+    fn get_message_ids(&self) -> Result<Vec<String>, MessageStoreError> {
+        self.msg_by_id
+            .iter()
+            .map(|item| {
+                item.and_then(|item| item.key::<String>()).map_err(|e| {
+                    MessageStoreError::CouldNotGetMessages(vec![format!(
+                        "Unable to read message ids due to {}",
+                        e
+                    )])
+                })
+            })
+            .collect()
+    }
+
     fn tag_message_id(
         &mut self,
         id: &str,
-        tags: std::collections::HashSet<String>,
+        tags: HashSet<String>,
     ) -> Result<(), MessageStoreError> {
-        todo!()
+        let msg = self
+            .get_message(id)?
+            .ok_or_else(|| MessageStoreError::MessageNotFound(id.to_string()))?;
+        self.tag_message(msg, tags).map(|_| ())
     }
     fn tag_message(
         &mut self,
-        msg: Message,
-        tags: std::collections::HashSet<String>,
+        mut msg: Message,
+        tags: HashSet<String>,
     ) -> Result<Message, MessageStoreError> {
-        todo!()
+        msg.apply_tags(&tags);
+        self.update_message(msg)
     }
 
-    fn list_tags(&self) -> Result<std::collections::HashSet<String>, MessageStoreError> {
-        todo!()
+    fn list_tags(&self) -> Result<HashSet<String>, MessageStoreError> {
+        self.count_tags().map(|counts| counts.into_keys().collect())
+    }
+
+    fn get_messages_by_id(&self, ids: HashSet<String>) -> Result<Vec<Message>, MessageStoreError> {
+        let mut msgs = vec![];
+        for id in ids {
+            if let Some(msg) = self.get_message(&id)? {
+                msgs.push(msg);
+            }
+        }
+        Ok(msgs)
+    }
+
+    fn count_tags(&self) -> Result<HashMap<String, usize>, MessageStoreError> {
+        self.ids_by_tag
+            .iter()
+            .map(|item| {
+                let item = item.map_err(|e| {
+                    MessageStoreError::CouldNotGetMessages(vec![format!(
+                        "Unable to read tags due to {}",
+                        e
+                    )])
+                })?;
+                let tag = item.key::<String>();
+                let ids = item.value::<Json<HashSet<String>>>();
+                match (tag, ids) {
+                    (Ok(tag), Ok(ids)) => Ok((tag, ids.0.len())),
+                    (Err(e), _) | (_, Err(e)) => Err(MessageStoreError::CouldNotGetMessages(
+                        vec![format!("Unable to read kv value: {}", e)],
+                    )),
+                }
+            })
+            .collect()
     }
 
     fn get_messages_by_tag(&self, tag: String) -> Result<Vec<Message>, MessageStoreError> {
-        todo!()
+        let ids = Self::get_id_set(&self.ids_by_tag, &tag).map_err(|e| {
+            MessageStoreError::CouldNotGetMessages(vec![format!(
+                "Unable to read tag {}: {}",
+                tag, e
+            )])
+        })?;
+        self.get_messages_by_id(ids)
+    }
+
+    fn get_thread_id(&self, message_id: &str) -> Result<Option<String>, MessageStoreError> {
+        self.thread_by_message_id
+            .get(&message_id.to_string())
+            .map_err(|e| {
+                MessageStoreError::CouldNotGetMessage(format!(
+                    "Unable to read the thread of {}: {}",
+                    message_id, e
+                ))
+            })
+    }
+
+    fn get_messages_by_thread(&self, thread: &str) -> Result<Vec<Message>, MessageStoreError> {
+        let ids = Self::get_id_set(&self.ids_by_thread, thread).map_err(|e| {
+            MessageStoreError::CouldNotGetMessages(vec![format!(
+                "Unable to read thread {}: {}",
+                thread, e
+            )])
+        })?;
+        self.get_messages_by_id(ids)
+    }
+
+    fn merge_threads(&mut self, from: &str, into: &str) -> Result<Vec<Message>, MessageStoreError> {
+        let err = |e: Error| {
+            MessageStoreError::CouldNotModifyMessage(format!(
+                "Unable to merge thread {} into {} in the KV store: {}",
+                from, into, e
+            ))
+        };
+        let mut moved = self.get_messages_by_thread(from)?;
+        let mut ids = Self::get_id_set(&self.ids_by_thread, into).map_err(err)?;
+        for msg in moved.iter_mut() {
+            msg.thread = into.to_string();
+            self.msg_by_id
+                .set(&msg.id, &Json(msg.clone()))
+                .map_err(err)?;
+            ids.insert(msg.id.clone());
+            let related = thread::ancestors(msg)
+                .into_iter()
+                .chain(Some(&msg.message_id).filter(|id| !id.is_empty()));
+            for message_id in related {
+                let current = self.thread_by_message_id.get(message_id).map_err(err)?;
+                if current.as_deref() == Some(from) {
+                    self.thread_by_message_id
+                        .set(message_id, &into.to_string())
+                        .map_err(err)?;
+                }
+            }
+        }
+        self.ids_by_thread
+            .set(&into.to_string(), &Json(ids))
+            .map_err(err)?;
+        self.ids_by_thread.remove(&from.to_string()).map_err(err)?;
+        Ok(moved)
+    }
+
+    fn contains(&self, id: &str) -> Result<bool, MessageStoreError> {
+        self.msg_by_id.contains(&id.to_string()).map_err(|e| {
+            MessageStoreError::CouldNotGetMessage(format!(
+                "Unable to get message from KV store: {}",
+                e
+            ))
+        })
+    }
+
+    fn get_indexed_paths(&self) -> Result<HashMap<PathBuf, IndexedPath>, MessageStoreError> {
+        self.by_path
+            .iter()
+            .map(|item| {
+                let item = item.map_err(|e| {
+                    MessageStoreError::CouldNotGetMessages(vec![format!(
+                        "Unable to read indexed paths due to {}",
+                        e
+                    )])
+                })?;
+                let path = item.key::<String>();
+                let indexed = item.value::<Json<IndexedPath>>();
+                match (path, indexed) {
+                    (Ok(path), Ok(indexed)) => Ok((PathBuf::from(path), indexed.0)),
+                    (Err(e), _) | (_, Err(e)) => Err(MessageStoreError::CouldNotGetMessages(
+                        vec![format!("Unable to read kv value: {}", e)],
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    fn remove_indexed_path(&mut self, path: &Path) -> Result<(), MessageStoreError> {
+        self.by_path
+            .remove(&path.to_string_lossy().into_owned())
+            .map(|_| ())
+            .map_err(|e| {
+                MessageStoreError::CouldNotDeleteMessage(format!(
+                    "Unable to forget the path {}: {}",
+                    path.display(),
+                    e
+                ))
+            })
+    }
+
+    fn get_correspondents(&self) -> Result<Vec<Correspondent>, MessageStoreError> {
+        self.correspondents
+            .iter()
+            .map(|item| {
+                item.and_then(|item| item.value::<Json<Correspondent>>())
+                    .map(|json| json.0)
+                    .map_err(|e| {
+                        MessageStoreError::CouldNotGetMessages(vec![format!(
+                            "Unable to read the address book due to {}",
+                            e
+                        )])
+                    })
+            })
+            .collect()
+    }
+
+    fn rebuild_address_book(&mut self) -> Result<usize, MessageStoreError> {
+        let err = |e: Error| {
+            MessageStoreError::CouldNotModifyMessage(format!(
+                "Unable to rebuild the address book: {}",
+                e
+            ))
+        };
+        let mut book: HashMap<String, Correspondent> = HashMap::new();
+        for item in self.msg_by_id.iter() {
+            let msg = item
+                .and_then(|item| item.value::<Json<Message>>())
+                .map_err(err)?
+                .0;
+            for (address, sent) in participants(&msg) {
+                book.entry(Correspondent::key(&address.email))
+                    .or_insert_with(|| Correspondent::new(&address.email))
+                    .record(address, sent, msg.date);
+            }
+        }
+        self.correspondents.clear().map_err(err)?;
+        for (key, correspondent) in book.iter() {
+            self.correspondents
+                .set(key, &Json(correspondent.clone()))
+                .map_err(err)?;
+        }
+        Ok(book.len())
     }
 
     fn add_messages(&mut self, msgs: Vec<Message>) {
@@ -128,10 +524,11 @@ impl<'a> crate::stores::kv::Kv for Kv<'a> {
 mod test {
     use std::collections::HashSet;
     use std::time::Instant;
+    use crate::stores::kv::Kv as _;
     use crate::stores::Store;
     use super::Kv;
 
-    use crate::message::{Body, Message, Mime};
+    use crate::message::{Address, Body, Message, Mime};
     use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
     fn get_rnd_str(num: usize) -> String {
@@ -142,7 +539,7 @@ mod test {
             .collect()
     }
 
-    fn get_store() -> crate::stores::kv::Kv<'static> {
+    fn get_store() -> Kv<'static> {
         let rand_string: String = thread_rng()
             .sample_iter(&Alphanumeric)
             .take(5)
@@ -158,18 +555,19 @@ mod test {
     fn get_rnd_msg() {
         let message = Message {
             id: "some_id".to_string(),
-            from: "It's me, Mario!".to_string(),
+            from: Address::parse("It's me, Mario!"),
             body: vec![Body {
                 mime: Mime::PlainText,
                 value: "Test body".to_string(),
             }],
             subject: "test_subject".to_string(),
-            recipients: vec!["r1".to_string(), "r2".to_string()],
+            to: vec![Address::parse("r1"), Address::parse("r2")],
             date: 4121251,
             original: vec![0],
             tags: vec!["tag1".to_string(), "tag2".to_string()]
                 .into_iter()
                 .collect::<HashSet<String>>(),
+            ..Default::default()
         };
     }
 
@@ -178,18 +576,19 @@ mod test {
         let mut store = get_store();
         let message = Message {
             id: "some_id".to_string(),
-            from: "It's me, Mario!".to_string(),
+            from: Address::parse("It's me, Mario!"),
             body: vec![Body {
                 mime: Mime::PlainText,
                 value: "Test body".to_string(),
             }],
             subject: "test_subject".to_string(),
-            recipients: vec!["r1".to_string(), "r2".to_string()],
+            to: vec![Address::parse("r1"), Address::parse("r2")],
             date: 4121251,
             original: vec![0],
             tags: vec!["tag1".to_string(), "tag2".to_string()]
                 .into_iter()
                 .collect::<HashSet<String>>(),
+            ..Default::default()
         };
         store.add_message(message.clone()).ok().unwrap();
 
@@ -206,18 +605,19 @@ mod test {
         let mut store = get_store();
         let message = Message {
             id: "some_id".to_string(),
-            from: "It's me, Mario!".to_string(),
+            from: Address::parse("It's me, Mario!"),
             body: vec![Body {
                 mime: Mime::PlainText,
                 value: "Test body".to_string(),
             }],
             subject: "test_subject".to_string(),
-            recipients: vec!["r1".to_string(), "r2".to_string()],
+            to: vec![Address::parse("r1"), Address::parse("r2")],
             date: 4121251,
             original: vec![0],
             tags: vec!["tag1".to_string(), "tag2".to_string()]
                 .into_iter()
                 .collect::<HashSet<String>>(),
+            ..Default::default()
         };
         store.add_message(message.clone()).unwrap();
 
@@ -237,18 +637,19 @@ mod test {
         let mut store = get_store();
         let message = Message {
             id: "some_id".to_string(),
-            from: "It's me, Mario!".to_string(),
+            from: Address::parse("It's me, Mario!"),
             body: vec![Body {
                 mime: Mime::PlainText,
                 value: "Test body".to_string(),
             }],
             subject: "test_subject".to_string(),
-            recipients: vec!["r1".to_string(), "r2".to_string()],
+            to: vec![Address::parse("r1"), Address::parse("r2")],
             date: 4121251,
             original: vec![0],
             tags: vec!["tag1".to_string(), "tag2".to_string()]
                 .into_iter()
                 .collect::<HashSet<String>>(),
+            ..Default::default()
         };
         store.add_message(message.clone()).unwrap();
 
@@ -261,4 +662,46 @@ mod test {
         let elapsed = now.elapsed().as_millis();
         assert!(elapsed < 300, "elapsed was {} > 300", elapsed);
     }
+
+    #[test]
+    fn can_tag_and_count_tags() {
+        let mut store = get_store();
+        let message = Message {
+            id: "tagged_id".to_string(),
+            subject: "test_subject".to_string(),
+            tags: vec!["inbox".to_string()].into_iter().collect::<HashSet<String>>(),
+            ..Default::default()
+        };
+        store.add_message(message).unwrap();
+        let changes = vec!["+work".to_string(), "-inbox".to_string()]
+            .into_iter()
+            .collect::<HashSet<String>>();
+        store.tag_message_id("tagged_id", changes).unwrap();
+
+        let retrieved = store.get_message("tagged_id").unwrap().unwrap();
+        assert!(retrieved.tags.contains("work"));
+        assert!(!retrieved.tags.contains("inbox"));
+        let counts = store.count_tags().unwrap();
+        assert_eq!(counts.get("work"), Some(&1));
+        assert_eq!(counts.get("inbox"), None);
+        assert_eq!(store.get_messages_by_tag("work".to_string()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lists_every_message_id() {
+        let mut store = get_store();
+        for id in ["first", "second", "third"] {
+            let message = Message {
+                id: id.to_string(),
+                ..Default::default()
+            };
+            store.add_message(message).unwrap();
+        }
+        let second = store.get_message("second").unwrap().unwrap();
+        store.delete_message(&second).unwrap();
+
+        let mut ids = store.get_message_ids().unwrap();
+        ids.sort();
+        assert_eq!(ids, vec!["first".to_string(), "third".to_string()]);
+    }
 }
@@ -1,18 +1,31 @@
+use crate::config::WriterConfig;
 use crate::message::{Message, MessageError};
-use crate::stores::search::Searcher;
+use crate::stores::query::{self, Expr, SearchField};
+use crate::stores::search::{Searcher, SortOrder};
 use crate::stores::{MessageStoreError, Store};
 use log::{error, info};
 use std::cmp;
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 use tantivy::collector::{Count, TopDocs};
 use tantivy::directory::MmapDirectory;
-use tantivy::query::{AllQuery, BooleanQuery, FuzzyTermQuery, Occur, Query, RegexQuery};
+use tantivy::fastfield::FastFieldReader;
+use tantivy::query::{
+    AllQuery, BooleanQuery, EmptyQuery, FuzzyTermQuery, Occur, PhraseQuery, Query, RangeQuery,
+    RegexQuery, TermQuery,
+};
 use tantivy::schema::*;
+use tantivy::tokenizer::TokenStream;
+use tantivy::{DocAddress, DocId, SegmentReader};
 const BYTES_IN_MB: usize = 1024 * 1024;
+/// Version of the fields of `EmailSchema`, to bump whenever they change: tantivy can't open an
+/// index with another schema than the one it was created with.
+const SCHEMA_VERSION: u32 = 5;
+const SCHEMA_VERSION_FILE: &str = "schema_version";
 
 pub type TantivyMessage = Message;
 
@@ -30,15 +43,28 @@ impl TantivyFrom<TantivyMessage> for TantivyMessage {
             _ => Err("Missing original email from the index"),
         };
 
-        let _tags: HashSet<String> = doc
+        let tags: HashSet<String> = doc
             .get_all(schema.tag)
             .into_iter()
             .filter_map(|s| s.as_text())
             .map(String::from)
             .collect();
-        TantivyMessage::from_data(
+        let mut msg = TantivyMessage::from_data(
             original.map_err(|_| MessageError::from("Could not read original from index"))?,
-        )
+        )?;
+        msg.tags = tags;
+        if let Some(thread) = doc.get_first(schema.thread).and_then(|t| t.as_text()) {
+            msg.thread = thread.to_string();
+        }
+        if let Some((maildir, folder)) = doc
+            .get_first(schema.mailbox)
+            .and_then(|m| m.as_text())
+            .and_then(|m| m.split_once('/'))
+        {
+            msg.maildir = maildir.to_string();
+            msg.folder = folder.to_string();
+        }
+        Ok(msg)
     }
 }
 
@@ -48,11 +74,17 @@ pub struct EmailSchema {
     body: Field,
     from: Field,
     recipients: Field,
-    #[allow(dead_code)]
+    from_email: Field,
+    to_email: Field,
     thread: Field,
     id: Field,
     date: Field,
     tag: Field,
+    has: Field,
+    attachment: Field,
+    folder: Field,
+    list: Field,
+    mailbox: Field,
     original: Field,
 }
 
@@ -63,9 +95,16 @@ impl Default for EmailSchema {
         let body = schema_builder.add_text_field("body", TEXT | STORED);
         let from = schema_builder.add_text_field("from", TEXT | STORED);
         let recipients = schema_builder.add_text_field("recipients", TEXT | STORED);
-        let thread = schema_builder.add_text_field("thread", STRING);
+        let from_email = schema_builder.add_text_field("from_email", STRING);
+        let to_email = schema_builder.add_text_field("to_email", STRING);
+        let thread = schema_builder.add_text_field("thread", STRING | STORED);
         let id = schema_builder.add_text_field("id", STRING | STORED);
         let tag = schema_builder.add_text_field("tag", STRING | STORED);
+        let has = schema_builder.add_text_field("has", STRING);
+        let attachment = schema_builder.add_text_field("attachment", TEXT);
+        let folder = schema_builder.add_text_field("folder", STRING);
+        let list = schema_builder.add_text_field("list", TEXT);
+        let mailbox = schema_builder.add_text_field("mailbox", STORED);
         let dateoptions = IntOptions::default()
             .set_fast(Cardinality::SingleValue)
             .set_stored()
@@ -79,10 +118,17 @@ impl Default for EmailSchema {
             body,
             from,
             recipients,
+            from_email,
+            to_email,
             thread,
             id,
             date,
             tag,
+            has,
+            attachment,
+            folder,
+            list,
+            mailbox,
             original,
         }
     }
@@ -98,17 +144,21 @@ pub struct TantivyStore {
     index: tantivy::Index,
     reader: tantivy::IndexReader,
     writer: Option<tantivy::IndexWriter>,
+    writer_config: WriterConfig,
 }
 
 impl Store for TantivyStore {
     fn add_message(&mut self, msg: Message) -> Result<Message, MessageStoreError> {
         self._add_message(msg)
     }
-    fn delete_message(&mut self, _msg: &Message) -> Result<(), MessageStoreError> {
-        unimplemented!();
+    fn delete_message(&mut self, msg: &Message) -> Result<(), MessageStoreError> {
+        self.start_indexing_process(1)?;
+        self._delete_message(msg)
     }
-    fn update_message(&mut self, _msg: Message) -> Result<Message, MessageStoreError> {
-        unimplemented!();
+    fn update_message(&mut self, msg: Message) -> Result<Message, MessageStoreError> {
+        self.start_indexing_process(1)?;
+        self._delete_message(&msg)?;
+        self._add_message(msg)
     }
 }
 impl Searcher for TantivyStore {
@@ -124,12 +174,40 @@ impl Searcher for TantivyStore {
         self._latest(num, None)
     }
 
+    fn query(
+        &self,
+        query: &str,
+        num: usize,
+        order: SortOrder,
+    ) -> Result<Vec<Message>, MessageStoreError> {
+        self._search(&query::parse(query)?, num, order)
+    }
+
+    fn count(&self, query: &str) -> Result<usize, MessageStoreError> {
+        self._count(&query::parse(query)?)
+    }
+
+    fn search_expr(
+        &self,
+        expr: &Expr,
+        num: usize,
+        order: SortOrder,
+    ) -> Result<Vec<Message>, MessageStoreError> {
+        self._search(expr, num, order)
+    }
+
     fn search_by_date(
         &self,
-        _start: chrono::DateTime<chrono::Utc>,
-        _end: chrono::DateTime<chrono::Utc>,
+        start: Option<chrono::DateTime<chrono::Utc>>,
+        end: Option<chrono::DateTime<chrono::Utc>>,
+        num: usize,
+        order: SortOrder,
     ) -> Result<Vec<Message>, MessageStoreError> {
-        todo!()
+        let expr = Expr::Date {
+            start: start.map(|d| d.timestamp().max(0) as u64),
+            end: end.map(|d| d.timestamp().max(0) as u64),
+        };
+        self._search(&expr, num, order)
     }
 
     fn start_index(&mut self, size_hint: usize) -> Result<(), MessageStoreError> {
@@ -139,23 +217,64 @@ impl Searcher for TantivyStore {
     fn finish_index (&mut self) -> Result<(), MessageStoreError> {
         self.finish_indexing_process()
     }
+
+    fn abort_index(&mut self) -> Result<(), MessageStoreError> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.rollback().map(|_| ()).map_err(|e| {
+                MessageStoreError::CouldNotModifyMessage(format!(
+                    "Failed to roll back the index: {}",
+                    e
+                ))
+            }),
+            None => Ok(()),
+        }
+    }
 }
 
 impl TantivyStore {
-    pub fn new(path: PathBuf) -> Self {
+    pub fn new(path: PathBuf) -> Result<Self, MessageStoreError> {
         let email = EmailSchema::default();
-        let index = TantivyStore::open_or_create_index(path, email.schema.clone());
-        let reader = index
-            .reader()
-            .expect("Unable to create an index reader for this index. Is the index corrupted?");
-        TantivyStore {
+        let index = TantivyStore::open_or_create_index(path, email.schema.clone())?;
+        let reader = index.reader().map_err(|e| {
+            MessageStoreError::CouldNotCreateSearcherError(format!(
+                "Unable to create an index reader, is the index corrupted? {}",
+                e
+            ))
+        })?;
+        Ok(TantivyStore {
             index,
             reader,
             writer: None,
+            writer_config: WriterConfig::default(),
             email,
+        })
+    }
+
+    /// Whether the index at `path` was created with another version of the schema. There is
+    /// nothing outdated about a missing index, it is created with the current one.
+    pub fn is_outdated(path: &Path) -> bool {
+        match fs::read_to_string(path.join(SCHEMA_VERSION_FILE)) {
+            Ok(version) => version.trim() != SCHEMA_VERSION.to_string(),
+            // Indexes created before the version was recorded.
+            Err(_) => path.join("meta.json").exists(),
         }
     }
 
+    /// Removes the index at `path`, so that it is created again with the current schema.
+    pub fn remove(path: &Path) -> Result<(), MessageStoreError> {
+        fs::remove_dir_all(path).map_err(|e| {
+            MessageStoreError::CouldNotCreateSearcherError(format!(
+                "Unable to remove the outdated index {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    pub fn set_writer_config(&mut self, config: WriterConfig) {
+        self.writer_config = config;
+    }
+
     fn start_indexing_process(&mut self, num: usize) -> Result<(), MessageStoreError> {
         if self.writer.is_none() {
             let writer = self.get_index_writer(num)?;
@@ -179,17 +298,35 @@ impl TantivyStore {
         }
     }
 
-    fn open_or_create(path: PathBuf, schema: Schema) -> tantivy::Index {
-        tantivy::Index::open_or_create(MmapDirectory::open(path).unwrap(), schema).unwrap()
+    fn open_or_create(path: PathBuf, schema: Schema) -> Result<tantivy::Index, MessageStoreError> {
+        let err = |e: String| {
+            MessageStoreError::CouldNotCreateSearcherError(format!(
+                "Unable to open the index {}: {}",
+                path.display(),
+                e
+            ))
+        };
+        let directory = MmapDirectory::open(&path).map_err(|e| err(e.to_string()))?;
+        let index =
+            tantivy::Index::open_or_create(directory, schema).map_err(|e| err(e.to_string()))?;
+        let version = path.join(SCHEMA_VERSION_FILE);
+        if !version.exists() {
+            fs::write(version, SCHEMA_VERSION.to_string()).map_err(|e| err(e.to_string()))?;
+        }
+        Ok(index)
     }
 
-    fn open_or_create_index(path: PathBuf, schema: Schema) -> tantivy::Index {
-        fs::create_dir_all(path.as_path()).unwrap_or_else(|_| {
-            panic!(
-                "Unable to create or access the given index directory {}",
-                path.to_str().unwrap()
-            )
-        });
+    fn open_or_create_index(
+        path: PathBuf,
+        schema: Schema,
+    ) -> Result<tantivy::Index, MessageStoreError> {
+        fs::create_dir_all(path.as_path()).map_err(|e| {
+            MessageStoreError::CouldNotCreateSearcherError(format!(
+                "Unable to create or access the index directory {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
         TantivyStore::open_or_create(path, schema)
     }
 
@@ -202,13 +339,35 @@ impl TantivyStore {
                 document.add_text(email.subject, msg.subject.as_str());
                 document.add_text(email.id, msg.id.as_str());
                 document.add_text(email.body, msg.get_body(None).as_text().as_str());
-                document.add_text(email.from, msg.from.as_str());
-                document.add_text(email.recipients, msg.recipients.join(", ").as_str());
+                document.add_text(email.from, msg.from.to_string().as_str());
+                document.add_text(email.from_email, msg.from.email.to_lowercase().as_str());
+                for recipient in msg.recipients() {
+                    document.add_text(email.recipients, recipient.to_string().as_str());
+                    document.add_text(email.to_email, recipient.email.to_lowercase().as_str());
+                }
                 document.add_bytes(email.original, msg.original.clone());
                 document.add_u64(email.date, msg.date);
+                document.add_text(email.thread, msg.thread.as_str());
                 msg.tags
                     .iter()
                     .for_each(|t| document.add_text(email.tag, t.as_str()));
+                if msg.has_attachment() {
+                    document.add_text(email.has, "attachment");
+                }
+                msg.attachments
+                    .iter()
+                    .for_each(|a| document.add_text(email.attachment, a.filename.as_str()));
+                if !msg.folder.is_empty() {
+                    let mailbox = format!("{}/{}", msg.maildir, msg.folder);
+                    // Folders match case insensitively, by name alone or prefixed by the maildir.
+                    for name in [&msg.maildir, &msg.folder, &mailbox] {
+                        document.add_text(email.folder, name.to_lowercase().as_str());
+                    }
+                    document.add_text(email.mailbox, mailbox.as_str());
+                }
+                if let Some(list_id) = msg.list_id.as_ref() {
+                    document.add_text(email.list, list_id.as_str());
+                }
                 indexer.add_document(document);
                 Ok(msg)
             }
@@ -230,32 +389,32 @@ impl TantivyStore {
             )),
         }
     }
-    pub fn _tag_doc(
-        &self,
-        doc: Document,
-        _tags: Vec<String>,
-    ) -> Result<(), tantivy::TantivyError> {
-        let mut writer = self.get_index_writer(1).ok().unwrap();
-        let id = TantivyMessage::from_tantivy(doc, &self.email)
-            .map_err(|_| {
-                tantivy::TantivyError::SystemError(String::from("Can't read message from index"))
-            })?
-            .id;
-        let term = Term::from_field_text(self.email.id, id.as_ref());
-        writer.delete_term(term);
-        writer.commit()?;
-        self.reader.reload()
+    pub fn _tag_doc(&mut self, doc: Document, tags: Vec<String>) -> Result<(), MessageStoreError> {
+        let mut msg = TantivyMessage::from_tantivy(doc, &self.email).map_err(|_| {
+            MessageStoreError::CouldNotModifyMessage(String::from("Can't read message from index"))
+        })?;
+        msg.tags = tags.into_iter().collect();
+        self.update_message(msg)?;
+        self.finish_indexing_process()?;
+        self.reader.reload().map_err(|e| {
+            MessageStoreError::CouldNotModifyMessage(format!("Unable to reload the index: {}", e))
+        })
     }
 
     fn get_index_writer(
         &self,
         num_emails: usize,
     ) -> Result<tantivy::IndexWriter, MessageStoreError> {
-        let num_cpu = cmp::min(
-            (num_cpus::get() as f32 / 1.5).floor() as usize,
-            cmp::max(1, (0.0818598 * (num_emails as f32).powf(0.311549)) as usize),
-        );
-        let mem_per_thread = (if let Ok(mem_info) = sys_info::mem_info() {
+        let num_cpu = self.writer_config.threads.unwrap_or_else(|| {
+            cmp::min(
+                (num_cpus::get() as f32 / 1.5).floor() as usize,
+                cmp::max(1, (0.0818598 * (num_emails as f32).powf(0.311549)) as usize),
+            )
+        })
+        .max(1);
+        let mem_per_thread = (if let Some(mb) = self.writer_config.memory_per_thread_mb {
+            mb * BYTES_IN_MB
+        } else if let Ok(mem_info) = sys_info::mem_info() {
             cmp::min(
                 cmp::min(
                     mem_info.avail as usize * 1024 / (num_cpu + 1),
@@ -312,6 +471,166 @@ impl TantivyStore {
         Ok(ret)
     }
 
+    fn text_query(&self, fields: &[Field], text: &str) -> Result<Box<dyn Query>, MessageStoreError> {
+        let mut queries: Vec<(Occur, Box<dyn Query>)> = vec![];
+        for field in fields {
+            let mut terms = vec![];
+            self.index
+                .tokenizer_for_field(*field)
+                .map_err(|e| MessageStoreError::InvalidQuery(e.to_string()))?
+                .token_stream(text)
+                .process(&mut |token| terms.push(Term::from_field_text(*field, &token.text)));
+            let q: Box<dyn Query> = match terms.len() {
+                0 => continue,
+                1 => Box::new(TermQuery::new(terms.remove(0), IndexRecordOption::Basic)),
+                _ => Box::new(PhraseQuery::new(terms)),
+            };
+            queries.push((Occur::Should, q));
+        }
+        Ok(match queries.len() {
+            0 => Box::new(EmptyQuery),
+            1 => queries.remove(0).1,
+            _ => Box::new(BooleanQuery::from(queries)),
+        })
+    }
+
+    fn build_query(&self, expr: &Expr) -> Result<Box<dyn Query>, MessageStoreError> {
+        let email = &self.email;
+        Ok(match expr {
+            Expr::All => Box::new(AllQuery),
+            Expr::Text {
+                field: Some(SearchField::Id),
+                value,
+            } => {
+                if !value.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(MessageStoreError::InvalidQuery(format!("Invalid id {}", value)));
+                }
+                Box::new(
+                    RegexQuery::from_pattern(format!("{}.*", value).as_str(), email.id)
+                        .map_err(|e| MessageStoreError::InvalidQuery(e.to_string()))?,
+                )
+            }
+            // A full address only matches the exact email, display names are searched as text.
+            Expr::Text {
+                field: Some(field @ (SearchField::From | SearchField::To)),
+                value,
+            } if value.contains('@') && !value.starts_with('@') => {
+                let exact = if *field == SearchField::From {
+                    email.from_email
+                } else {
+                    email.to_email
+                };
+                let address = value.trim_matches(&['<', '>'][..]).to_lowercase();
+                Box::new(TermQuery::new(
+                    Term::from_field_text(exact, &address),
+                    IndexRecordOption::Basic,
+                ))
+            }
+            Expr::Text {
+                field: Some(SearchField::Folder),
+                value,
+            } => Box::new(TermQuery::new(
+                Term::from_field_text(email.folder, &value.to_lowercase()),
+                IndexRecordOption::Basic,
+            )),
+            Expr::Text { field, value } => {
+                let fields = match field {
+                    None => vec![email.subject, email.body, email.from, email.recipients],
+                    Some(SearchField::From) => vec![email.from],
+                    Some(SearchField::To) => vec![email.recipients],
+                    Some(SearchField::Subject) => vec![email.subject],
+                    Some(SearchField::Body) => vec![email.body],
+                    Some(SearchField::Tag) => vec![email.tag],
+                    Some(SearchField::Thread) => vec![email.thread],
+                    Some(SearchField::Attachment) => vec![email.attachment],
+                    Some(SearchField::Folder) => vec![email.folder],
+                    Some(SearchField::List) => vec![email.list],
+                    Some(SearchField::Id) => vec![email.id],
+                };
+                self.text_query(&fields, value)?
+            }
+            Expr::Date { start, end } => Box::new(RangeQuery::new_u64(
+                email.date,
+                start.unwrap_or(0)..end.unwrap_or(u64::MAX),
+            )),
+            Expr::Has(value) => Box::new(TermQuery::new(
+                Term::from_field_text(email.has, value),
+                IndexRecordOption::Basic,
+            )),
+            Expr::Not(inner) => Box::new(BooleanQuery::from(vec![
+                (Occur::Must, Box::new(AllQuery) as Box<dyn Query>),
+                (Occur::MustNot, self.build_query(inner)?),
+            ])),
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                let occur = if let Expr::And(_) = expr {
+                    Occur::Must
+                } else {
+                    Occur::Should
+                };
+                let queries = exprs
+                    .iter()
+                    .map(|e| self.build_query(e).map(|q| (occur, q)))
+                    .collect::<Result<Vec<(Occur, Box<dyn Query>)>, MessageStoreError>>()?;
+                Box::new(BooleanQuery::from(queries))
+            }
+        })
+    }
+
+    pub fn _count(&self, expr: &Expr) -> Result<usize, MessageStoreError> {
+        let query = self.build_query(expr)?;
+        self.reader
+            .searcher()
+            .search(&*query, &Count)
+            .map_err(|e| MessageStoreError::InvalidQuery(e.to_string()))
+    }
+
+    pub fn _search(
+        &self,
+        expr: &Expr,
+        num: usize,
+        order: SortOrder,
+    ) -> Result<Vec<TantivyMessage>, MessageStoreError> {
+        let query = self.build_query(expr)?;
+        let searcher = self.reader.searcher();
+        let addresses: Vec<DocAddress> = match order {
+            SortOrder::Descending => searcher
+                .search(&*query, &TopDocs::with_limit(num).order_by_u64_field(self.email.date))
+                .map(|docs| docs.into_iter().map(|(_, address)| address).collect()),
+            SortOrder::Ascending => {
+                let dates = searcher
+                    .segment_readers()
+                    .iter()
+                    .map(|segment_reader| {
+                        let dates = segment_reader.fast_fields().u64(self.email.date)?;
+                        Ok((segment_reader.segment_id(), dates))
+                    })
+                    .collect::<tantivy::Result<HashMap<_, _>>>()
+                    .map_err(|e| MessageStoreError::CouldNotGetMessages(vec![e.to_string()]))?;
+                let oldest_first = TopDocs::with_limit(num).custom_score(
+                    move |segment_reader: &SegmentReader| {
+                        // The readers were read from the segments being searched.
+                        let dates = dates.get(&segment_reader.segment_id()).cloned();
+                        move |doc: DocId| Reverse(dates.as_ref().map_or(0, |d| d.get(doc)))
+                    },
+                );
+                searcher
+                    .search(&*query, &oldest_first)
+                    .map(|docs| docs.into_iter().map(|(_, address)| address).collect())
+            }
+        }
+        .map_err(|e| MessageStoreError::InvalidQuery(e.to_string()))?;
+        let mut ret = vec![];
+        for address in addresses {
+            let retrieved_doc = searcher
+                .doc(address)
+                .map_err(|e| MessageStoreError::CouldNotGetMessage(e.to_string()))?;
+            if let Ok(d) = TantivyMessage::from_tantivy(retrieved_doc, &self.email) {
+                ret.push(d);
+            }
+        }
+        Ok(ret)
+    }
+
     pub fn get_doc(&self, id: &str) -> Result<Document, tantivy::TantivyError> {
         // Is this needed? self.reader.load_searchers()?;
         let searcher = self.reader.searcher();
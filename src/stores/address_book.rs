@@ -0,0 +1,209 @@
+use crate::message::{Address, Message};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Someone seen in the From, To, Cc or Bcc headers of indexed messages.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Correspondent {
+    pub email: String,
+    /// Display names in the order they were first seen.
+    pub names: Vec<String>,
+    /// Messages sent by this correspondent.
+    pub sent: usize,
+    /// Messages addressed to this correspondent.
+    pub received: usize,
+    pub last_seen: u64,
+}
+
+impl Correspondent {
+    pub fn new(email: &str) -> Self {
+        Correspondent {
+            email: email.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn key(email: &str) -> String {
+        email.trim().to_lowercase()
+    }
+
+    pub fn record(&mut self, address: &Address, sent: bool, date: u64) {
+        if let Some(name) = address.name.as_ref() {
+            if !self.names.contains(name) {
+                self.names.push(name.clone());
+            }
+        }
+        if sent {
+            self.sent += 1;
+        } else {
+            self.received += 1;
+        }
+        self.last_seen = self.last_seen.max(date);
+    }
+
+    pub fn forget(&mut self, sent: bool) {
+        if sent {
+            self.sent = self.sent.saturating_sub(1);
+        } else {
+            self.received = self.received.saturating_sub(1);
+        }
+    }
+
+    pub fn is_unused(&self) -> bool {
+        self.sent == 0 && self.received == 0
+    }
+
+    pub fn name(&self) -> &str {
+        self.names.first().map(String::as_str).unwrap_or("")
+    }
+}
+
+/// The addresses of a message along with whether they sent it. Each correspondent appears
+/// at most once per role.
+pub fn participants(msg: &Message) -> Vec<(&Address, bool)> {
+    let mut seen = HashSet::new();
+    Some(&msg.from)
+        .into_iter()
+        .map(|a| (a, true))
+        .chain(msg.recipients().map(|a| (a, false)))
+        .filter(|(a, sent)| {
+            !a.email.is_empty() && seen.insert((Correspondent::key(&a.email), *sent))
+        })
+        .collect()
+}
+
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut chars = haystack.chars();
+    needle.chars().all(|n| chars.any(|h| h == n))
+}
+
+fn is_prefix_match(correspondent: &Correspondent, query: &str) -> bool {
+    correspondent.email.to_lowercase().starts_with(query)
+        || correspondent.names.iter().any(|name| {
+            let name = name.to_lowercase();
+            name.starts_with(query) || name.split_whitespace().any(|w| w.starts_with(query))
+        })
+}
+
+fn is_fuzzy_match(correspondent: &Correspondent, query: &str) -> bool {
+    let local = correspondent.email.split('@').next().unwrap_or("");
+    is_subsequence(
+        query,
+        &format!("{} {}", correspondent.names.join(" "), local).to_lowercase(),
+    )
+}
+
+/// Correspondents whose email or a name starts with `query`, or with `fuzzy` also those whose
+/// names or email user contain its characters in order. Prefix matches come first, then the
+/// most frequent correspondents.
+pub fn lookup<'a>(
+    entries: &'a [Correspondent],
+    query: &str,
+    fuzzy: bool,
+) -> Vec<&'a Correspondent> {
+    let query = query.trim().to_lowercase();
+    let mut found = entries
+        .iter()
+        .filter_map(|c| {
+            if is_prefix_match(c, &query) {
+                Some((0, c))
+            } else if fuzzy && is_fuzzy_match(c, &query) {
+                Some((1, c))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<(u8, &Correspondent)>>();
+    found.sort_by_key(|(rank, c)| (*rank, Reverse(c.sent + c.received), c.email.clone()));
+    found.into_iter().map(|(_, c)| c).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressFormat {
+    /// The format of mutt's `query_command`: a status line, then tab separated
+    /// email, name and details.
+    Mutt,
+    Json,
+}
+
+#[derive(Debug)]
+pub struct AddressFormatError(String);
+
+impl fmt::Display for AddressFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown address format {}, use mutt or json", self.0)
+    }
+}
+impl std::error::Error for AddressFormatError {}
+
+impl std::str::FromStr for AddressFormat {
+    type Err = AddressFormatError;
+    fn from_str(input: &str) -> Result<AddressFormat, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "mutt" => Ok(AddressFormat::Mutt),
+            "json" => Ok(AddressFormat::Json),
+            _ => Err(AddressFormatError(input.to_string())),
+        }
+    }
+}
+
+pub fn format(found: &[&Correspondent], format: AddressFormat) -> String {
+    match format {
+        AddressFormat::Json => serde_json::to_string_pretty(found).unwrap_or_default(),
+        AddressFormat::Mutt => {
+            let mut lines = vec![format!("rms: {} matching addresses", found.len())];
+            lines.extend(found.iter().map(|c| {
+                format!(
+                    "{}\t{}\tsent {}, received {}",
+                    c.email,
+                    c.name(),
+                    c.sent,
+                    c.received
+                )
+            }));
+            lines.join("\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lookup, Correspondent};
+
+    fn correspondent(email: &str, names: &[&str], count: usize) -> Correspondent {
+        Correspondent {
+            email: email.to_string(),
+            names: names.iter().map(|n| n.to_string()).collect(),
+            sent: count,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn finds_prefix_then_fuzzy_matches() {
+        let entries = vec![
+            correspondent("alice@example.com", &["Alice Doe"], 1),
+            correspondent("doe.family@example.com", &[], 5),
+            correspondent("bob@example.com", &["Bob Smith"], 9),
+            correspondent("adam.lee@example.com", &["Adam Lee"], 2),
+        ];
+        let emails = |found: Vec<&Correspondent>| {
+            found
+                .into_iter()
+                .map(|c| c.email.clone())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            emails(lookup(&entries, "Doe", false)),
+            vec!["doe.family@example.com", "alice@example.com"]
+        );
+        assert_eq!(emails(lookup(&entries, "ale", false)), Vec::<String>::new());
+        assert_eq!(
+            emails(lookup(&entries, "ale", true)),
+            vec!["adam.lee@example.com", "alice@example.com"]
+        );
+        assert_eq!(emails(lookup(&entries, "", false)).len(), 4);
+    }
+}
@@ -1,14 +1,25 @@
-use std::collections::HashSet;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
 
 use crate::message::Message;
 
+use super::address_book::Correspondent;
 use super::Store;
 use super::MessageStoreError;
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexedPath {
+    pub id: String,
+    pub mtime: u64,
+}
+
 pub trait Kv: Store {
     fn get_message(&self, id: &str) -> Result<Option<Message>, MessageStoreError>;
     fn get_messages(&self, start: usize, num: usize) -> Result<Vec<Message>, MessageStoreError>;
+    /// Ids of every stored message, read in a single pass over the store.
+    fn get_message_ids(&self) -> Result<Vec<String>, MessageStoreError>;
     fn tag_message_id(
         &mut self,
         id: &str,
@@ -22,8 +33,20 @@ pub trait Kv: Store {
     ) -> Result<Message, MessageStoreError>;
 
     fn list_tags(&self) -> Result<HashSet<String>, MessageStoreError>;
+    fn count_tags(&self) -> Result<HashMap<String, usize>, MessageStoreError>;
     fn get_messages_by_tag(&self, tag: String) -> Result<Vec<Message>, MessageStoreError>;
+    fn get_messages_by_id(&self, ids: HashSet<String>) -> Result<Vec<Message>, MessageStoreError>;
+    fn get_thread_id(&self, message_id: &str) -> Result<Option<String>, MessageStoreError>;
+    fn get_messages_by_thread(&self, thread: &str) -> Result<Vec<Message>, MessageStoreError>;
+    /// Moves every message of the thread `from` into the thread `into` and returns the moved
+    /// messages.
+    fn merge_threads(&mut self, from: &str, into: &str) -> Result<Vec<Message>, MessageStoreError>;
     fn add_messages(&mut self, msgs: Vec<Message>);
+    fn contains(&self, id: &str) -> Result<bool, MessageStoreError>;
+    fn get_indexed_paths(&self) -> Result<HashMap<PathBuf, IndexedPath>, MessageStoreError>;
+    fn remove_indexed_path(&mut self, path: &Path) -> Result<(), MessageStoreError>;
+    fn get_correspondents(&self) -> Result<Vec<Correspondent>, MessageStoreError>;
+    fn rebuild_address_book(&mut self) -> Result<usize, MessageStoreError>;
 }
 
 pub fn default_kv<'a>(path: PathBuf) -> Result<super::_impl::kv::Kv<'a>, kv::Error> {
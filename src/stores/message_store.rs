@@ -1,23 +1,75 @@
 use pbr::ProgressBar;
-use crate::message::maildir::{mailentry_iterator, parse_message};
-use crate::message::Message;
+use crate::config::Config;
+use crate::message::maildir::{
+    deliver, file_mtime, flag_tags, folder_path, folders, is_new, mailentry_iterator,
+    move_to_cur, parse_message, parse_path, set_location, sync_file_flags, sync_flag_tags,
+    tag_flags, trash_file, MaildirError,
+};
+use crate::message::thread::{ancestors, root_thread_id, sort_thread};
+use crate::message::{Message, MessageError};
+use crate::stores::query::{self, matcher};
+use crate::stores::report::{IndexFailure, IndexReport};
+use crate::stores::rules::Rules;
+use crate::stores::spam::{self, SpamFilter, SpamModel, HAM_TAG, SPAM_TAG};
 use crate::stores::MessageStoreError;
 use crate::stores::_impl::kv;
 use crate::stores::_impl::tantivy::TantivyStore;
+use log::{error, info};
 use maildir_ext::Maildir;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
 use rayon::prelude::*;
 
 
 
 
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use super::kv::Kv;
 use super::search::Searcher;
 use super::Store;
 
+const WATCH_BATCH_SIZE: usize = 50;
+const WATCH_BATCH_DELAY: Duration = Duration::from_secs(5);
+/// Messages read at once when going through the whole store.
+const STORE_PAGE_SIZE: usize = 500;
+
+pub enum MailFileAction {
+    Remove,
+    Trash,
+    Keep,
+}
+
+/// Why a message could not be inserted, telling apart bad input from failures worth retrying.
+#[derive(Debug)]
+pub enum InsertError {
+    NotAMessage(MessageError),
+    CouldNotDeliver(io::Error),
+    Store(MessageStoreError),
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InsertError::NotAMessage(e) => write!(f, "Not a valid message: {}", e.message),
+            InsertError::CouldNotDeliver(e) => write!(f, "Could not write the message: {}", e),
+            InsertError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<MessageStoreError> for InsertError {
+    fn from(e: MessageStoreError) -> Self {
+        InsertError::Store(e)
+    }
+}
+
 pub struct MessageStore<S, K>
 where
     S: Searcher,
@@ -25,6 +77,9 @@ where
 {
     pub searcher: S,
     pub kv: K,
+    maildir_names: HashMap<PathBuf, String>,
+    rules: Rules,
+    spam: SpamFilter,
 }
 
 impl<S, K> Store for MessageStore<S, K>
@@ -32,17 +87,81 @@ where
     S: Searcher,
     K: Kv,
 {
-    fn add_message(&mut self, msg: Message) -> Result<Message, MessageStoreError> {
+    fn add_message(&mut self, mut msg: Message) -> Result<Message, MessageStoreError> {
+        self.assign_thread(&mut msg)?;
         self.searcher.add_message(msg.clone())?; //TODO remove clone
         self.kv.add_message(msg)
     }
 
     fn update_message(&mut self, m: Message) -> Result<Message, MessageStoreError> {
-        self.searcher.update_message(m)
+        self.searcher.update_message(m.clone())?;
+        self.kv.update_message(m)
+    }
+
+    fn delete_message(&mut self, msg: &Message) -> Result<(), MessageStoreError> {
+        // Nothing is staged in the index until the KV has let go of the message.
+        let stored = self.kv.get_message(&msg.id)?;
+        self.kv.delete_message(msg)?;
+        let deleted = self
+            .searcher
+            .delete_message(msg)
+            .and_then(|_| self.searcher.finish_index());
+        if let Err(e) = deleted {
+            // The index still holds the message, put it back so both stores agree.
+            self.searcher.abort_index()?;
+            if let Some(stored) = stored {
+                self.kv.add_message(stored)?;
+            }
+            return Err(e);
+        }
+        Ok(())
     }
+}
 
-    fn delete_message(&mut self, _msg: &Message) -> Result<(), MessageStoreError> {
-        unimplemented!();
+impl<S, K> MessageStore<S, K>
+where
+    S: Searcher,
+    K: Kv,
+{
+    fn assign_thread(&mut self, msg: &mut Message) -> Result<(), MessageStoreError> {
+        if !msg.thread.is_empty() {
+            return Ok(());
+        }
+        // Replies indexed before their parents may have started threads of their own, this
+        // message links them so they are merged into the thread closest to the root.
+        let related = ancestors(msg)
+            .into_iter()
+            .chain(Some(&msg.message_id).filter(|id| !id.is_empty()))
+            .cloned()
+            .collect::<Vec<String>>();
+        let mut threads: Vec<String> = vec![];
+        for message_id in related {
+            if let Some(thread) = self.kv.get_thread_id(&message_id)? {
+                if !threads.contains(&thread) {
+                    threads.push(thread);
+                }
+            }
+        }
+        let mut threads = threads.into_iter();
+        msg.thread = match threads.next() {
+            Some(thread) => thread,
+            None => root_thread_id(msg),
+        };
+        for other in threads {
+            for moved in self.kv.merge_threads(&other, &msg.thread)? {
+                self.searcher.update_message(moved)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_thread(&self, msg: &Message) -> Result<Vec<(usize, Message)>, MessageStoreError> {
+        let msgs = if msg.thread.is_empty() {
+            vec![msg.clone()]
+        } else {
+            self.kv.get_messages_by_thread(&msg.thread)?
+        };
+        Ok(sort_thread(msgs))
     }
 }
 
@@ -50,20 +169,183 @@ impl MessageStore<TantivyStore, kv::Kv<'_>> {
     pub fn new(path: PathBuf) -> Result<Self, MessageStoreError> {
         let tantivy_path = path.join("index/");
         let kv_path = path.join("store/");
-        let tantivy = TantivyStore::new(tantivy_path);
+        let rebuild = TantivyStore::is_outdated(&tantivy_path);
+        if rebuild {
+            info!("The index was built by another version of rms, rebuilding it");
+            TantivyStore::remove(&tantivy_path)?;
+        }
+        let tantivy = TantivyStore::new(tantivy_path)?;
         let kv = kv::Kv::new(kv_path).map_err(|_| {
             MessageStoreError::CouldNotCreateKvError("Couldn't create kv".to_string())
         })?;
-        Ok(MessageStore {
+        let spam = SpamFilter::load(path.join("spam.json"))?;
+        let mut store = MessageStore {
             searcher: tantivy,
             kv,
-        })
+            maildir_names: HashMap::new(),
+            rules: Rules::default(),
+            spam,
+        };
+        if rebuild {
+            store.rebuild_index()?;
+        }
+        Ok(store)
+    }
+
+    /// Indexes every message of the KV again, into an index created with the current schema.
+    fn rebuild_index(&mut self) -> Result<(), MessageStoreError> {
+        let ids = self.kv.get_message_ids()?;
+        self.start_indexing_process(ids.len())?;
+        for chunk in ids.chunks(STORE_PAGE_SIZE) {
+            for msg in self.get_stored_messages(chunk)? {
+                self.searcher.add_message(msg)?;
+            }
+        }
+        self.finish_indexing_process()
+    }
+
+    /// Reads the messages of `ids` from the KV, skipping those deleted since.
+    fn get_stored_messages(&self, ids: &[String]) -> Result<Vec<Message>, MessageStoreError> {
+        self.kv.get_messages_by_id(ids.iter().cloned().collect())
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Result<Self, MessageStoreError> {
+        self.searcher.set_writer_config(config.writer.clone());
+        self.rules = Rules::new(&config.rules)?;
+        self.spam.threshold = config.spam.threshold;
+        self.maildir_names = config
+            .maildirs
+            .iter()
+            .map(|(name, path)| (path.clone(), name.clone()))
+            .collect();
+        Ok(self)
+    }
+
+    pub fn get_message(&self, id: &str) -> Result<Option<Message>, MessageStoreError> {
+        match self.kv.get_message(id)? {
+            Some(msg) => Ok(Some(msg)),
+            None => match self.searcher._get_message(id) {
+                Some(found) => self.kv.get_message(&found.id),
+                None => Ok(None),
+            },
+        }
+    }
+
+    pub fn delete_message_by_id(
+        &mut self,
+        id: &str,
+        action: MailFileAction,
+    ) -> Result<Message, MessageStoreError> {
+        let msg = self
+            .get_message(id)?
+            .ok_or_else(|| MessageStoreError::MessageNotFound(id.to_string()))?;
+        self.delete_message(&msg)?;
+        if let Some(path) = msg.path.as_ref().filter(|p| p.exists()) {
+            let res = match action {
+                MailFileAction::Remove => fs::remove_file(path),
+                MailFileAction::Trash => trash_file(path).map(|_| ()),
+                MailFileAction::Keep => Ok(()),
+            };
+            res.map_err(|e| {
+                MessageStoreError::CouldNotDeleteMessage(format!(
+                    "Removed {} from the index but not its file {}: {}",
+                    msg.id,
+                    path.display(),
+                    e
+                ))
+            })?;
+        }
+        Ok(msg)
+    }
+
+    pub fn tag_message_id(
+        &mut self,
+        id: &str,
+        tags: HashSet<String>,
+    ) -> Result<Message, MessageStoreError> {
+        let mut msg = self
+            .get_message(id)?
+            .ok_or_else(|| MessageStoreError::MessageNotFound(id.to_string()))?;
+        let confirmed = spam::confirm_tags(&mut msg, &tags);
+        if msg.apply_tags(&tags) || confirmed {
+            msg = self.save_tags(msg)?;
+            self.searcher.finish_index()?;
+        }
+        Ok(msg)
+    }
+
+    /// Stores the new tags of an indexed message and writes its flags to its file. The index
+    /// still has to be committed.
+    fn save_tags(&mut self, mut msg: Message) -> Result<Message, MessageStoreError> {
+        let previous_path = msg.path.clone();
+        sync_file_flags(&mut msg).map_err(|e| {
+            MessageStoreError::CouldNotModifyMessage(format!(
+                "Unable to update the maildir flags of {}: {}",
+                msg.id, e
+            ))
+        })?;
+        msg = self.update_message(msg)?;
+        if let Some(previous) = previous_path.filter(|p| Some(p) != msg.path.as_ref()) {
+            self.kv.remove_indexed_path(&previous)?;
+        }
+        Ok(msg)
+    }
+
+    /// Applies the tagging rules again to the indexed messages matching the `search` query, or
+    /// to every message. Returns the number of messages whose tags changed.
+    pub fn retag(&mut self, search: Option<&str>) -> Result<usize, MessageStoreError> {
+        let filter = search.map(query::parse).transpose()?;
+        if self.rules.is_empty() {
+            return Ok(0);
+        }
+        let mut changed = 0;
+        let ids = self.kv.get_message_ids()?;
+        for chunk in ids.chunks(STORE_PAGE_SIZE) {
+            for mut msg in self.get_stored_messages(chunk)? {
+                if filter.as_ref().map_or(true, |f| matcher::matches(f, &msg))
+                    && self.rules.apply(&mut msg)
+                {
+                    self.save_tags(msg)?;
+                    changed += 1;
+                }
+            }
+        }
+        if changed > 0 {
+            self.searcher.finish_index()?;
+        }
+        Ok(changed)
+    }
+
+    /// Trains the spam filter again from every message tagged `spam` or `ham` and saves it.
+    /// Messages the filter itself tagged are left out. Returns the number of spam and ham
+    /// messages learned.
+    pub fn train_spam(&mut self) -> Result<(u64, u64), MessageStoreError> {
+        let mut model = SpamModel::default();
+        let ids = self.kv.get_message_ids()?;
+        for chunk in ids.chunks(STORE_PAGE_SIZE) {
+            for msg in self.get_stored_messages(chunk)?.iter() {
+                let spam = msg.tags.contains(SPAM_TAG);
+                // Conflicting tags teach nothing.
+                if !msg.auto_spam && spam != msg.tags.contains(HAM_TAG) {
+                    model.learn(&self.spam.tokens(msg), spam);
+                }
+            }
+        }
+        self.spam.model = model;
+        self.spam.save()?;
+        Ok((self.spam.model.spam_messages, self.spam.model.ham_messages))
+    }
+
+    /// Probability that `msg` is spam according to the spam filter as trained now.
+    pub fn spam_score(&self, msg: &Message) -> Option<f64> {
+        self.spam.score(msg)
     }
+
     pub async fn add_maildir(
         &mut self,
         path: PathBuf,
         all: bool,
-    ) -> Result<usize, MessageStoreError> {
+    ) -> Result<IndexReport, MessageStoreError> {
         self.index_mails(path, all).await
     }
     fn maildir(&mut self, path: PathBuf) -> Result<Maildir, ()> {
@@ -88,58 +370,414 @@ impl MessageStore<TantivyStore, kv::Kv<'_>> {
     //    });
     //    Ok(1)
     //}
+    /// Applies the tagging rules to a message about to be indexed. Flags changed by the rules
+    /// are written to its file.
+    fn apply_rules(&self, msg: &mut Message) {
+        if self.rules.apply(msg) {
+            if let Err(e) = sync_file_flags(msg) {
+                error!("Unable to update the maildir flags of {}: {}", msg.id, e);
+            }
+        }
+    }
+
+    /// Indexes a freshly parsed message of the maildir at `root` unless its content is already
+    /// known, in which case only the location of its file is updated.
+    fn index_parsed(
+        &mut self,
+        mut msg: Message,
+        new: bool,
+        root: &Path,
+        report: &mut IndexReport,
+    ) -> Result<(), MessageStoreError> {
+        if let Some(name) = self.maildir_names.get(root) {
+            msg.maildir = name.clone();
+        }
+        if new {
+            if let Some(path) = msg.path.clone() {
+                match move_to_cur(&path) {
+                    Ok(moved) => msg.path = Some(moved),
+                    Err(e) => report.move_failed.push(IndexFailure {
+                        error: MaildirError::FailedToMoveParsedMailEntry(
+                            format!("Failed to move {} to cur", msg.id),
+                            e,
+                        ),
+                        path,
+                    }),
+                }
+            }
+        }
+        match self.kv.get_message(&msg.id)? {
+            Some(mut existing) => {
+                // Same content at a second path that still exists is a duplicate, otherwise the
+                // file was renamed or re-flagged by another client, or changed in place.
+                let duplicate = existing
+                    .path
+                    .as_ref()
+                    .map_or(false, |path| Some(path) != msg.path.as_ref() && path.exists());
+                let moved = existing.maildir != msg.maildir || existing.folder != msg.folder;
+                existing.path = msg.path;
+                existing.mtime = msg.mtime;
+                existing.maildir = msg.maildir;
+                existing.folder = msg.folder;
+                // Flags may have been changed by another client since the last run.
+                let retagged = sync_flag_tags(&mut existing);
+                if moved || retagged {
+                    self.update_message(existing)?;
+                } else {
+                    self.kv.update_message(existing)?;
+                }
+                if duplicate {
+                    report.duplicates += 1;
+                } else {
+                    report.updated += 1;
+                }
+            }
+            None => {
+                self.spam.classify(&mut msg);
+                self.apply_rules(&mut msg);
+                if !msg.warnings.is_empty() {
+                    report.decoded_lossily += 1;
+                }
+                self.add_message(msg)?;
+                report.added += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops index entries of files under `root` that no longer exist. A message is only
+    /// removed once none of its known files are left.
+    fn reconcile(&mut self, root: &Path) -> Result<usize, MessageStoreError> {
+        let indexed = self.kv.get_indexed_paths()?;
+        let mut remaining: HashMap<&str, Vec<&PathBuf>> = HashMap::new();
+        let mut missing = vec![];
+        for (path, entry) in indexed.iter() {
+            if !path.starts_with(root) || path.exists() {
+                remaining.entry(entry.id.as_str()).or_default().push(path);
+            } else {
+                missing.push((path, entry));
+            }
+        }
+        let mut removed = 0;
+        for (path, entry) in missing {
+            self.kv.remove_indexed_path(path)?;
+            let msg = match self.kv.get_message(&entry.id)? {
+                Some(msg) => msg,
+                None => continue,
+            };
+            match remaining.get(entry.id.as_str()).and_then(|paths| paths.first()) {
+                Some(other) if msg.path.as_ref() == Some(path) => {
+                    let mut msg = msg;
+                    msg.path = Some(other.to_path_buf());
+                    self.kv.update_message(msg)?;
+                }
+                Some(_) => {}
+                None => {
+                    self.kv.delete_message(&msg)?;
+                    self.searcher.delete_message(&msg)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
     async fn do_index_mails(
         &mut self,
         maildir: Maildir,
+        root: &Path,
         full: bool,
-    ) -> Result<usize, MessageStoreError> {
+    ) -> Result<IndexReport, MessageStoreError> {
+        let mut known = self.kv.get_indexed_paths()?;
+        // Files of deleted messages have to be looked at again.
+        known.retain(|_, indexed| self.kv.contains(&indexed.id).unwrap_or(false));
+        let dir = maildir.path().to_path_buf();
+        let parse_root = root.to_path_buf();
         let (iter, count) = mailentry_iterator(&maildir, full);
         self.start_indexing_process(count)?;
 
         let (tx, rx) = mpsc::channel();
         //let count = iter.size_hint().1.unwrap_or_default();
         let mut pb = ProgressBar::new(count as u64);
-        let handle = thread::spawn(|| {
+        let handle = thread::spawn(move || {
             iter.par_bridge().for_each_with(tx, |tx, m| {
-                m.map(parse_message).map(|x| tx.send(x)).ok();
+                let (path, unchanged) = match m.as_ref() {
+                    Ok(entry) => {
+                        let path = entry.0.path().clone();
+                        let unchanged = known
+                            .get(&path)
+                            .map_or(false, |indexed| indexed.mtime == file_mtime(&path));
+                        (path, unchanged)
+                    }
+                    Err(_) => (dir.clone(), false),
+                };
+                let parsed = if unchanged {
+                    None
+                } else {
+                    Some(m.and_then(|m| parse_message(m, &parse_root)))
+                };
+                tx.send((path, parsed)).ok();
             });
         });
-        while let Ok(x) = rx.recv() {
-            if let Ok((msg, new)) = x {
-                let id = msg.id.clone();
-                    self.add_message(msg)?;
-                    if new {
-                        maildir
-                            .move_new_to_cur(&id)
-                            .map_err(MessageStoreError::FailedToMoveParsedMailEntry)?;
-                    }
-                
-            } else {
-                println!("Failed to add/move message");
+        let mut report = IndexReport::default();
+        while let Ok((path, x)) = rx.recv() {
+            match x {
+                None => report.skipped += 1,
+                Some(Ok((msg, new))) => self.index_parsed(msg, new, root, &mut report)?,
+                Some(Err(error)) => report.parse_failed.push(IndexFailure { path, error }),
             }
             pb.inc();
         }
 
         handle.join().unwrap();
         pb.finish_print("done");
-        self.finish_indexing_process()?;
-        Ok(10)
+        Ok(report)
     }
 
+    /// Indexes the maildir at `path` and its Maildir++ subfolders.
     pub async fn index_mails(
         &mut self,
         path: PathBuf,
         full: bool,
-    ) -> Result<usize, MessageStoreError> {
-        let maildir = self.maildir(path);
-        match maildir {
-            Ok(maildir) => {
-                self.do_index_mails(maildir, full).await?;
-                Ok(1)
+    ) -> Result<IndexReport, MessageStoreError> {
+        let mut report = IndexReport::default();
+        for folder in folders(&path) {
+            let maildir = self.maildir(folder).map_err(|_| {
+                MessageStoreError::CouldNotOpenMaildir("Failed to read maildir".to_string())
+            })?;
+            report.merge(self.do_index_mails(maildir, &path, full).await?);
+        }
+        // Only look for removed files once every folder is indexed, messages moved between
+        // folders would be dropped otherwise.
+        report.removed = self.reconcile(&path)?;
+        self.finish_indexing_process()?;
+        Ok(report)
+    }
+
+    /// Indexes message files of the maildirs at `roots`.
+    fn index_paths(
+        &mut self,
+        paths: Vec<PathBuf>,
+        roots: &[PathBuf],
+    ) -> Result<IndexReport, MessageStoreError> {
+        self.start_indexing_process(paths.len())?;
+        let mut report = IndexReport::default();
+        for path in paths {
+            // The innermost root, should a maildir be configured inside another.
+            let root = match roots
+                .iter()
+                .filter(|root| path.starts_with(root))
+                .max_by_key(|root| root.components().count())
+            {
+                Some(root) => root,
+                None => continue,
+            };
+            match parse_path(&path, root) {
+                Ok((msg, new)) => self.index_parsed(msg, new, root, &mut report)?,
+                Err(error) => report.parse_failed.push(IndexFailure { path, error }),
+            }
+        }
+        self.finish_indexing_process()?;
+        Ok(report)
+    }
+
+    /// Indexes raw messages read from something else than a maildir, such as an mbox. New
+    /// messages get the tag changes of `tags`. Failures are reported against `source`.
+    pub fn import_messages<I>(
+        &mut self,
+        messages: I,
+        source: &Path,
+        tags: &HashSet<String>,
+    ) -> Result<IndexReport, MessageStoreError>
+    where
+        I: Iterator<Item = io::Result<Vec<u8>>>,
+    {
+        self.start_indexing_process(0)?;
+        let mut report = IndexReport::default();
+        for (i, data) in messages.enumerate() {
+            let parsed = data
+                .map_err(|e| {
+                    let entry = format!("Failed to read message {}", i + 1);
+                    MaildirError::FailedToReadMailEntry(entry, e)
+                })
+                .and_then(|data| {
+                    Message::from_data(data).map_err(MaildirError::FailedToParseMailEntry)
+                });
+            let mut msg = match parsed {
+                Ok(msg) => msg,
+                Err(error) => {
+                    report.parse_failed.push(IndexFailure {
+                        path: source.to_path_buf(),
+                        error,
+                    });
+                    continue;
+                }
+            };
+            if self.kv.contains(&msg.id)? {
+                report.duplicates += 1;
+                continue;
+            }
+            self.spam.classify(&mut msg);
+            self.apply_rules(&mut msg);
+            spam::confirm_tags(&mut msg, tags);
+            msg.apply_tags(tags);
+            if !msg.warnings.is_empty() {
+                report.decoded_lossily += 1;
             }
-            Err(_) => Err(MessageStoreError::CouldNotOpenMaildir(
-                "Failed to read maildir".to_string(),
-            )),
+            self.add_message(msg)?;
+            report.added += 1;
         }
+        self.finish_indexing_process()?;
+        Ok(report)
+    }
+
+    /// Delivers a message to the Maildir++ `folder` of the maildir at `root` and indexes it. It
+    /// is unread unless the tag changes of `tags`, applied on top, say otherwise. Returns `None`
+    /// without writing anything when the message is already indexed. The file is removed again
+    /// when the message could not be indexed, so that the delivery can be retried.
+    pub fn insert_message(
+        &mut self,
+        data: Vec<u8>,
+        root: &Path,
+        folder: &str,
+        tags: &HashSet<String>,
+    ) -> Result<Option<Message>, InsertError> {
+        let mut msg = Message::from_data(data).map_err(InsertError::NotAMessage)?;
+        if self.kv.contains(&msg.id)? {
+            return Ok(None);
+        }
+        msg.tags = flag_tags("");
+        self.spam.classify(&mut msg);
+        self.rules.apply(&mut msg);
+        spam::confirm_tags(&mut msg, tags);
+        msg.apply_tags(tags);
+        let dir = folder_path(root, folder);
+        let path = deliver(&dir, &msg.original, &tag_flags(&msg.tags, ""))
+            .map_err(InsertError::CouldNotDeliver)?;
+        msg.mtime = file_mtime(&path);
+        set_location(&mut msg, &path, root);
+        if let Some(name) = self.maildir_names.get(root) {
+            msg.maildir = name.clone();
+        }
+        msg.path = Some(path.clone());
+        let indexed = self
+            .start_indexing_process(1)
+            .and_then(|_| self.add_message(msg))
+            .and_then(|msg| self.finish_indexing_process().map(|_| msg));
+        match indexed {
+            Ok(msg) => Ok(Some(msg)),
+            Err(e) => {
+                let _ = fs::remove_file(&path);
+                Err(InsertError::Store(e))
+            }
+        }
+    }
+
+    /// Indexes messages as they are delivered to the `new/` and `cur/` folders of the given
+    /// maildirs. New messages are committed in batches and never returns unless the watcher
+    /// fails.
+    pub fn watch_maildirs(&mut self, maildirs: &[PathBuf]) -> Result<(), MessageStoreError> {
+        let watch_err = |e: notify::Error| MessageStoreError::CouldNotWatchMaildir(e.to_string());
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = watcher(tx, Duration::from_secs(1)).map_err(watch_err)?;
+        for maildir in maildirs.iter().flat_map(|root| folders(root)) {
+            for sub in ["new", "cur"] {
+                watcher
+                    .watch(maildir.join(sub), RecursiveMode::NonRecursive)
+                    .map_err(watch_err)?;
+            }
+            info!("Watching {}", maildir.display());
+        }
+
+        let mut pending: HashSet<PathBuf> = HashSet::new();
+        // Files of `new/` indexed here, which the indexer itself then moves to `cur/`.
+        let mut moving: HashSet<PathBuf> = HashSet::new();
+        let mut batch_started = Instant::now();
+        loop {
+            match rx.recv_timeout(WATCH_BATCH_DELAY) {
+                Ok(DebouncedEvent::Create(path)) => {
+                    pending.insert(path);
+                }
+                // Renamed files are picked up again so that the index follows them, unless they
+                // were only moved out of `new/` by the indexer.
+                Ok(DebouncedEvent::Rename(from, to)) => {
+                    if !moving.remove(&from) {
+                        pending.insert(to);
+                    }
+                }
+                Ok(DebouncedEvent::Error(e, path)) => {
+                    error!("Watch error on {:?}: {}", path, e);
+                }
+                Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(MessageStoreError::CouldNotWatchMaildir(
+                        "The filesystem watcher stopped".to_string(),
+                    ));
+                }
+            }
+            if pending.is_empty() {
+                batch_started = Instant::now();
+            } else if pending.len() >= WATCH_BATCH_SIZE
+                || batch_started.elapsed() >= WATCH_BATCH_DELAY
+            {
+                let batch = pending.drain().filter(|p| p.exists()).collect::<Vec<PathBuf>>();
+                moving.extend(batch.iter().filter(|p| is_new(p)).cloned());
+                // A failing batch is logged rather than ending the watch.
+                let report = match self.index_paths(batch, maildirs) {
+                    Ok(report) => report,
+                    Err(e) => {
+                        error!("Failed to index new messages: {}", e);
+                        continue;
+                    }
+                };
+                info!("Indexed {} new messages", report.added);
+                report
+                    .parse_failed
+                    .iter()
+                    .chain(report.move_failed.iter())
+                    .for_each(|f| error!("{}: {}", f.path.display(), f.error));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageStore;
+    use crate::message::Message;
+    use crate::stores::kv::Kv;
+    use crate::stores::search::{Searcher, SortOrder};
+    use crate::stores::Store;
+    use tempdir::TempDir;
+
+    fn reply(id: &str, parent: Option<&str>) -> Message {
+        Message {
+            id: id.to_string(),
+            message_id: format!("{}@example.com", id),
+            in_reply_to: parent.map(|p| format!("{}@example.com", p)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merges_threads_indexed_out_of_order() {
+        let dir = TempDir::new("rms-threads").unwrap();
+        let mut store = MessageStore::new(dir.path().to_path_buf()).unwrap();
+        store.start_indexing_process(3).unwrap();
+        let msgs = vec![reply("a", None), reply("c", Some("b")), reply("b", Some("a"))];
+        for msg in msgs {
+            store.add_message(msg).unwrap();
+        }
+        store.finish_indexing_process().unwrap();
+
+        let root = store.kv.get_message("a").unwrap().unwrap();
+        let thread = store.get_thread(&root).unwrap();
+        assert_eq!(thread.len(), 3);
+        assert!(thread.iter().all(|(_, m)| m.thread == root.thread));
+        let indexed = store
+            .searcher
+            .query(&format!("thread:{}", root.thread), 10, SortOrder::Descending)
+            .unwrap();
+        assert_eq!(indexed.len(), 3);
     }
 }
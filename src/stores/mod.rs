@@ -3,9 +3,14 @@ use crate::message::Message;
 use std::fmt;
 
 pub mod _impl;
+pub mod address_book;
 pub mod kv;
 pub mod message_store;
+pub mod query;
+pub mod report;
+pub mod rules;
 pub mod search;
+pub mod spam;
 
 #[derive(Debug)]
 pub enum MessageStoreError {
@@ -21,6 +26,9 @@ pub enum MessageStoreError {
     CouldNotCreateSearcherError(String),
     FailedToMoveParsedMailEntry(std::io::Error),
     InvalidQuery(String),
+    CouldNotWatchMaildir(String),
+    CouldNotLoadSpamModel(String),
+    CouldNotSaveSpamModel(String),
 }
 
 pub trait Store {
@@ -42,7 +50,7 @@ impl fmt::Display for MessageStoreError {
                 format!("Could not get messages {}", s.join(", "))
             }
             MessageStoreError::CouldNotGetMessage(s) => format!("Could not get message {}", s),
-            MessageStoreError::InvalidQuery(s) => format!("Could query message {}", s),
+            MessageStoreError::InvalidQuery(s) => format!("Invalid query: {}", s),
             MessageStoreError::CouldNotConvertMessage(s) => {
                 format!("Could not convert message {}", s)
             }
@@ -53,12 +61,19 @@ impl fmt::Display for MessageStoreError {
             MessageStoreError::CouldNotCreateKvError(_) => {
                 "Could not create the KV store".to_string()
             }
-            MessageStoreError::CouldNotCreateSearcherError(_) => {
-                "Could not create the Search store".to_string()
+            MessageStoreError::CouldNotCreateSearcherError(s) => {
+                format!("Could not create the Search store: {}", s)
             }
             MessageStoreError::FailedToMoveParsedMailEntry(_) => {
                 "Could not move parsed mail entry".to_string()
             }
+            MessageStoreError::CouldNotWatchMaildir(s) => format!("Could not watch maildir {}", s),
+            MessageStoreError::CouldNotLoadSpamModel(s) => {
+                format!("Could not load spam model {}", s)
+            }
+            MessageStoreError::CouldNotSaveSpamModel(s) => {
+                format!("Could not save spam model {}", s)
+            }
         };
         write!(f, "Message Store Error {}", msg)
     }
@@ -0,0 +1,155 @@
+use chrono::prelude::*;
+use chrono::{Duration, LocalResult};
+
+fn local_timestamp(date: NaiveDate) -> i64 {
+    let naive = date.and_hms(0, 0, 0);
+    match Local.from_local_datetime(&naive) {
+        LocalResult::Single(d) | LocalResult::Ambiguous(d, _) => d.timestamp(),
+        LocalResult::None => naive.timestamp(),
+    }
+}
+
+fn next_month(year: i32, month: u32) -> Option<NaiveDate> {
+    if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)
+    }
+}
+
+fn prev_month(year: i32, month: u32) -> Option<NaiveDate> {
+    if month == 1 {
+        NaiveDate::from_ymd_opt(year - 1, 12, 1)
+    } else {
+        NaiveDate::from_ymd_opt(year, month - 1, 1)
+    }
+}
+
+/// Parses `30d`, `12h`, `2w`, `6m` or `1y` into a duration. Months are 30 days, years 365.
+fn parse_duration(input: &str) -> Option<Duration> {
+    let unit = input.chars().last()?;
+    let num = input[..input.len() - unit.len_utf8()].parse::<i64>().ok()?;
+    match unit {
+        'h' => Some(Duration::hours(num)),
+        'd' => Some(Duration::days(num)),
+        'w' => Some(Duration::weeks(num)),
+        'm' => Some(Duration::days(num * 30)),
+        'y' => Some(Duration::days(num * 365)),
+        _ => None,
+    }
+}
+
+/// Parses `YYYY`, `YYYY-MM`, `YYYY-MM-DD` or a relative period (`today`, `yesterday`,
+/// `this week`, `last month`, ...) into the timestamps of its first second and of the first
+/// second of the following period.
+fn parse_period(input: &str, now: DateTime<Local>) -> Result<(i64, i64), String> {
+    let invalid = || format!("Invalid date {}", input);
+    let today = now.date().naive_local();
+    let week_start = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    let month_start = NaiveDate::from_ymd_opt(today.year(), today.month(), 1).ok_or_else(invalid)?;
+    let year_start = NaiveDate::from_ymd_opt(today.year(), 1, 1).ok_or_else(invalid)?;
+    let normalized = input.to_lowercase().split_whitespace().collect::<Vec<&str>>().join(" ");
+    let period = match normalized.as_str() {
+        "now" => return Ok((now.timestamp(), now.timestamp())),
+        "today" => today.succ_opt().map(|end| (today, end)),
+        "yesterday" => today.pred_opt().map(|start| (start, today)),
+        "this week" => Some((week_start, week_start + Duration::weeks(1))),
+        "last week" => Some((week_start - Duration::weeks(1), week_start)),
+        "this month" => next_month(today.year(), today.month()).map(|end| (month_start, end)),
+        "last month" => prev_month(today.year(), today.month()).map(|start| (start, month_start)),
+        "this year" => NaiveDate::from_ymd_opt(today.year() + 1, 1, 1).map(|end| (year_start, end)),
+        "last year" => NaiveDate::from_ymd_opt(today.year() - 1, 1, 1).map(|start| (start, year_start)),
+        _ => {
+            let parts = normalized
+                .split('-')
+                .map(|p| p.parse::<u32>().map_err(|_| invalid()))
+                .collect::<Result<Vec<u32>, String>>()?;
+            match parts.as_slice() {
+                [y] => NaiveDate::from_ymd_opt(*y as i32, 1, 1)
+                    .and_then(|d| Some((d, NaiveDate::from_ymd_opt(*y as i32 + 1, 1, 1)?))),
+                [y, m] => NaiveDate::from_ymd_opt(*y as i32, *m, 1)
+                    .and_then(|d| Some((d, next_month(*y as i32, *m)?))),
+                [y, m, d] => NaiveDate::from_ymd_opt(*y as i32, *m, *d)
+                    .and_then(|d| Some((d, d.succ_opt()?))),
+                _ => None,
+            }
+        }
+    };
+    period
+        .map(|(start, end)| (local_timestamp(start), local_timestamp(end)))
+        .ok_or_else(invalid)
+}
+
+fn to_bound(ts: i64) -> u64 {
+    ts.max(0) as u64
+}
+
+/// Parses a date expression into unix timestamp bounds, the end being exclusive. Accepts a
+/// single period (`2024-03`, `yesterday`, `last week`), a `start..end` range where either
+/// side may be omitted, or an age: `<30d` (newer than), `>30d` (older than) or just `30d`.
+pub fn parse_range_at(
+    input: &str,
+    now: DateTime<Local>,
+) -> Result<(Option<u64>, Option<u64>), String> {
+    let input = input.trim();
+    if let Some((start, end)) = input.split_once("..") {
+        let start = match start.trim() {
+            "" => None,
+            s => Some(to_bound(parse_period(s, now)?.0)),
+        };
+        let end = match end.trim() {
+            "" => None,
+            e => Some(to_bound(parse_period(e, now)?.1)),
+        };
+        return Ok((start, end));
+    }
+    let (older, age) = match input.strip_prefix('>') {
+        Some(age) => (true, age),
+        None => (false, input.strip_prefix('<').unwrap_or(input)),
+    };
+    if let Some(duration) = parse_duration(age.trim()) {
+        let limit = Some(to_bound((now - duration).timestamp()));
+        return Ok(if older { (None, limit) } else { (limit, None) });
+    }
+    let (start, end) = parse_period(input, now)?;
+    Ok((Some(to_bound(start)), Some(to_bound(end))))
+}
+
+pub fn parse_range(input: &str) -> Result<(Option<u64>, Option<u64>), String> {
+    parse_range_at(input, Local::now())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_range, parse_range_at};
+    use chrono::prelude::*;
+
+    #[test]
+    fn month_range_covers_whole_months() {
+        let (start, end) = parse_range("2023-01..2023-06").unwrap();
+        let (jan, _) = parse_range("2023-01-01").unwrap();
+        let (jul, _) = parse_range("2023-07-01").unwrap();
+        assert_eq!(start, jan);
+        assert_eq!(end, jul);
+    }
+
+    #[test]
+    fn open_ranges_and_errors() {
+        assert_eq!(parse_range("..2023").unwrap().0, None);
+        assert_eq!(parse_range("2023..").unwrap().1, None);
+        assert!(parse_range("2023-13").is_err());
+        assert!(parse_range("someday").is_err());
+    }
+
+    #[test]
+    fn relative_dates() {
+        let now = Local.ymd(2024, 3, 14).and_hms(15, 0, 0);
+        let day = |d| Some(Local.ymd(2024, 3, d).and_hms(0, 0, 0).timestamp() as u64);
+        assert_eq!(parse_range_at("yesterday", now).unwrap(), (day(13), day(14)));
+        assert_eq!(parse_range_at("Last  Week", now).unwrap(), (day(4), day(11)));
+        assert_eq!(parse_range_at("yesterday..today", now).unwrap(), (day(13), day(15)));
+        let ten_days_ago = Some((now.timestamp() - 10 * 24 * 3600) as u64);
+        assert_eq!(parse_range_at("<10d", now).unwrap(), (ten_days_ago, None));
+        assert_eq!(parse_range_at(">10d", now).unwrap(), (None, ten_days_ago));
+    }
+}
@@ -0,0 +1,130 @@
+use super::{Expr, SearchField};
+use crate::message::Message;
+
+/// Lowercase words of `text`, split the way the index splits text fields.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty() && w.len() < 40)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Whether the words of `value` follow each other in `text`, as a phrase search would match.
+fn contains_phrase(text: &str, value: &str) -> bool {
+    let phrase = words(value);
+    !phrase.is_empty()
+        && words(text)
+            .windows(phrase.len())
+            .any(|window| window == phrase.as_slice())
+}
+
+fn body(msg: &Message) -> String {
+    if msg.body.is_empty() {
+        String::new()
+    } else {
+        msg.get_body(None).as_text()
+    }
+}
+
+fn matches_text(field: Option<SearchField>, value: &str, msg: &Message) -> bool {
+    match field {
+        None => {
+            contains_phrase(&msg.subject, value)
+                || contains_phrase(&body(msg), value)
+                || contains_phrase(&msg.from.to_string(), value)
+                || msg
+                    .recipients()
+                    .any(|r| contains_phrase(&r.to_string(), value))
+        }
+        // A full address only matches the exact email, display names are matched as text.
+        Some(SearchField::From) | Some(SearchField::To)
+            if value.contains('@') && !value.starts_with('@') =>
+        {
+            let address = value.trim_matches(&['<', '>'][..]).to_lowercase();
+            if field == Some(SearchField::From) {
+                msg.from.email.to_lowercase() == address
+            } else {
+                msg.recipients().any(|r| r.email.to_lowercase() == address)
+            }
+        }
+        Some(SearchField::From) => contains_phrase(&msg.from.to_string(), value),
+        Some(SearchField::To) => msg
+            .recipients()
+            .any(|r| contains_phrase(&r.to_string(), value)),
+        Some(SearchField::Subject) => contains_phrase(&msg.subject, value),
+        Some(SearchField::Body) => contains_phrase(&body(msg), value),
+        Some(SearchField::Tag) => msg.tags.contains(value),
+        Some(SearchField::Thread) => msg.thread == value,
+        Some(SearchField::Attachment) => msg
+            .attachments
+            .iter()
+            .any(|a| contains_phrase(&a.filename, value)),
+        Some(SearchField::Folder) => {
+            let value = value.to_lowercase();
+            let mailbox = format!("{}/{}", msg.maildir, msg.folder);
+            !msg.folder.is_empty()
+                && [&msg.maildir, &msg.folder, &mailbox]
+                    .iter()
+                    .any(|name| name.to_lowercase() == value)
+        }
+        Some(SearchField::List) => msg
+            .list_id
+            .as_ref()
+            .map_or(false, |list_id| contains_phrase(list_id, value)),
+        Some(SearchField::Id) => {
+            value.chars().all(|c| c.is_ascii_hexdigit()) && msg.id.starts_with(value)
+        }
+    }
+}
+
+/// Whether `msg` is one of the messages a search for `expr` finds, without going through the
+/// index. Used on messages before they are indexed, such as by tagging rules.
+pub fn matches(expr: &Expr, msg: &Message) -> bool {
+    match expr {
+        Expr::All => true,
+        Expr::Text { field, value } => matches_text(*field, value, msg),
+        Expr::Date { start, end } => {
+            start.map_or(true, |start| msg.date >= start) && end.map_or(true, |end| msg.date < end)
+        }
+        Expr::Has(value) => value == "attachment" && msg.has_attachment(),
+        Expr::Not(inner) => !matches(inner, msg),
+        Expr::And(exprs) => exprs.iter().all(|e| matches(e, msg)),
+        Expr::Or(exprs) => exprs.iter().any(|e| matches(e, msg)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+    use crate::message::address::Address;
+    use crate::message::Message;
+    use crate::stores::query::parse;
+
+    #[test]
+    fn matches_messages_like_the_index() {
+        let msg = Message {
+            id: "0123abcd".to_string(),
+            subject: "Weekly build report".to_string(),
+            from: Address::parse("CI Bot <ci-bot@example.com>"),
+            to: vec![Address::parse("dev@example.com")],
+            list_id: Some("rust-users.lists.rust-lang.org".to_string()),
+            tags: vec!["inbox".to_string()].into_iter().collect(),
+            maildir: "work".to_string(),
+            folder: "INBOX".to_string(),
+            date: 1_700_000_000,
+            ..Default::default()
+        };
+        let matching = |query: &str| matches(&parse(query).unwrap(), &msg);
+        assert!(matching("from:ci-bot@example.com"));
+        assert!(matching("from:\"ci bot\" subject:report"));
+        assert!(matching("list:rust-users tag:inbox"));
+        assert!(matching("folder:work/inbox"));
+        assert!(matching("id:0123 OR tag:spam"));
+        assert!(matching("\"build report\" -tag:spam"));
+        assert!(!matching("\"report build\""));
+        assert!(!matching("from:bot@example.com"));
+        assert!(!matching("list:rust-internals"));
+        assert!(!matching("has:attachment"));
+        assert!(!matching("date:2024-01-01.."));
+    }
+}
@@ -0,0 +1,295 @@
+use super::MessageStoreError;
+use std::iter::Peekable;
+use std::str::Chars;
+
+pub mod date;
+pub mod matcher;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchField {
+    From,
+    To,
+    Subject,
+    Body,
+    Tag,
+    Thread,
+    Attachment,
+    Folder,
+    List,
+    Id,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    All,
+    Text {
+        field: Option<SearchField>,
+        value: String,
+    },
+    Date {
+        start: Option<u64>,
+        end: Option<u64>,
+    },
+    Has(String),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Term { key: Option<String>, value: String },
+}
+
+fn invalid(msg: String) -> MessageStoreError {
+    MessageStoreError::InvalidQuery(msg)
+}
+
+fn read_quoted(chars: &mut Peekable<Chars>) -> Result<String, MessageStoreError> {
+    chars.next();
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => {
+                if let Some(c) = chars.next() {
+                    value.push(c);
+                }
+            }
+            Some(c) => value.push(c),
+            None => return Err(invalid(format!("Unterminated quote in \"{}", value))),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, MessageStoreError> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '"' => {
+                let value = read_quoted(&mut chars)?;
+                tokens.push(Token::Term { key: None, value });
+            }
+            '-' => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                let token = match word.as_str() {
+                    "AND" | "and" => Token::And,
+                    "OR" | "or" => Token::Or,
+                    "NOT" | "not" => Token::Not,
+                    _ => match word.split_once(':') {
+                        Some((key, value))
+                            if !key.is_empty() && key.chars().all(char::is_alphabetic) =>
+                        {
+                            let value = if value.is_empty() && chars.peek() == Some(&'"') {
+                                read_quoted(&mut chars)?
+                            } else {
+                                value.to_string()
+                            };
+                            Token::Term {
+                                key: Some(key.to_lowercase()),
+                                value,
+                            }
+                        }
+                        _ => Token::Term {
+                            key: None,
+                            value: word,
+                        },
+                    },
+                };
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, MessageStoreError> {
+        let mut exprs = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            exprs.push(self.parse_and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Expr::Or(exprs)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, MessageStoreError> {
+        let mut exprs = vec![self.parse_unary()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                }
+                Some(Token::Not) | Some(Token::LParen) | Some(Token::Term { .. }) => {}
+                _ => break,
+            }
+            exprs.push(self.parse_unary()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            Expr::And(exprs)
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, MessageStoreError> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(invalid("Missing closing parenthesis".to_string())),
+                }
+            }
+            Some(Token::Term { key, value }) => term(key, value),
+            Some(t) => Err(invalid(format!("Unexpected {:?}", t))),
+            None => Err(invalid("Unexpected end of query".to_string())),
+        }
+    }
+}
+
+fn term(key: Option<String>, value: String) -> Result<Expr, MessageStoreError> {
+    let field = match key.as_deref() {
+        None if value == "*" => return Ok(Expr::All),
+        None => None,
+        Some("from") => Some(SearchField::From),
+        Some("to") => Some(SearchField::To),
+        Some("subject") => Some(SearchField::Subject),
+        Some("body") => Some(SearchField::Body),
+        Some("tag") => Some(SearchField::Tag),
+        Some("thread") => Some(SearchField::Thread),
+        Some("attachment") => Some(SearchField::Attachment),
+        Some("folder") => Some(SearchField::Folder),
+        Some("list") => Some(SearchField::List),
+        Some("id") => Some(SearchField::Id),
+        Some("date") => {
+            let (start, end) = date::parse_range(&value).map_err(invalid)?;
+            return Ok(Expr::Date { start, end });
+        }
+        Some("has") => {
+            return match value.to_lowercase().as_str() {
+                "attachment" => Ok(Expr::Has("attachment".to_string())),
+                _ => Err(invalid(format!("Unknown has:{}", value))),
+            }
+        }
+        Some(k) => return Err(invalid(format!("Unknown field {}", k))),
+    };
+    if value.is_empty() {
+        return Err(invalid(format!("Missing value for {}:", key.unwrap_or_default())));
+    }
+    Ok(Expr::Text { field, value })
+}
+
+pub fn parse(input: &str) -> Result<Expr, MessageStoreError> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Err(invalid("Empty query".to_string()));
+    }
+    let expr = parser.parse_or()?;
+    match parser.next() {
+        None => Ok(expr),
+        Some(t) => Err(invalid(format!("Unexpected {:?}", t))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Expr, SearchField};
+
+    fn text(field: Option<SearchField>, value: &str) -> Expr {
+        Expr::Text {
+            field,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn implicit_and_binds_tighter_than_or() {
+        let expr = parse("from:alice subject:report OR tag:inbox").unwrap();
+        assert_eq!(
+            expr,
+            Expr::Or(vec![
+                Expr::And(vec![
+                    text(Some(SearchField::From), "alice"),
+                    text(Some(SearchField::Subject), "report"),
+                ]),
+                text(Some(SearchField::Tag), "inbox"),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_negation_parentheses_and_phrases() {
+        let expr = parse("NOT (tag:spam or -tag:list) subject:\"weekly report\"").unwrap();
+        assert_eq!(
+            expr,
+            Expr::And(vec![
+                Expr::Not(Box::new(Expr::Or(vec![
+                    text(Some(SearchField::Tag), "spam"),
+                    Expr::Not(Box::new(text(Some(SearchField::Tag), "list"))),
+                ]))),
+                text(Some(SearchField::Subject), "weekly report"),
+            ])
+        );
+    }
+
+    #[test]
+    fn reports_invalid_queries() {
+        assert!(parse("(tag:inbox").is_err());
+        assert!(parse("subject:\"unterminated").is_err());
+        assert!(parse("nope:value").is_err());
+        assert!(parse("has:nothing").is_err());
+        assert!(parse("").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::message::maildir::MaildirError;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+
+fn serialize_error<S: Serializer>(error: &MaildirError, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(error)
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexFailure {
+    pub path: PathBuf,
+    #[serde(serialize_with = "serialize_error")]
+    pub error: MaildirError,
+}
+
+/// Outcome of an indexing run, one or more maildirs merged together.
+#[derive(Debug, Default, Serialize)]
+pub struct IndexReport {
+    /// Messages added to the index.
+    pub added: usize,
+    /// Files already indexed and untouched since.
+    pub skipped: usize,
+    /// Indexed messages whose file was renamed, moved or had its flags changed.
+    pub updated: usize,
+    /// Files whose content is also indexed from another path that still exists.
+    pub duplicates: usize,
+    /// Messages dropped because their files disappeared.
+    pub removed: usize,
+    /// Added messages with decoding warnings, such as an unknown charset.
+    pub decoded_lossily: usize,
+    pub parse_failed: Vec<IndexFailure>,
+    pub move_failed: Vec<IndexFailure>,
+}
+
+impl IndexReport {
+    pub fn has_failures(&self) -> bool {
+        !self.parse_failed.is_empty() || !self.move_failed.is_empty()
+    }
+
+    pub fn merge(&mut self, other: IndexReport) {
+        self.added += other.added;
+        self.skipped += other.skipped;
+        self.updated += other.updated;
+        self.duplicates += other.duplicates;
+        self.removed += other.removed;
+        self.decoded_lossily += other.decoded_lossily;
+        self.parse_failed.extend(other.parse_failed);
+        self.move_failed.extend(other.move_failed);
+    }
+}
+
+impl fmt::Display for IndexReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Added: {}", self.added)?;
+        writeln!(f, "Unchanged: {}", self.skipped)?;
+        writeln!(f, "Updated: {}", self.updated)?;
+        writeln!(f, "Duplicates: {}", self.duplicates)?;
+        writeln!(f, "Removed: {}", self.removed)?;
+        writeln!(f, "Decoded with warnings: {}", self.decoded_lossily)?;
+        writeln!(f, "Failed to parse: {}", self.parse_failed.len())?;
+        for failure in &self.parse_failed {
+            writeln!(f, "  {}: {}", failure.path.display(), failure.error)?;
+        }
+        write!(f, "Failed to move: {}", self.move_failed.len())?;
+        for failure in &self.move_failed {
+            write!(f, "\n  {}: {}", failure.path.display(), failure.error)?;
+        }
+        Ok(())
+    }
+}
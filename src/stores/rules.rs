@@ -0,0 +1,80 @@
+use super::query::{self, matcher, Expr};
+use super::MessageStoreError;
+use crate::config::TagRule;
+use crate::message::Message;
+use std::collections::HashSet;
+
+/// The tagging rules of the config, their queries parsed.
+#[derive(Debug, Default, Clone)]
+pub struct Rules(Vec<(Expr, HashSet<String>)>);
+
+impl Rules {
+    pub fn new(rules: &[TagRule]) -> Result<Rules, MessageStoreError> {
+        rules
+            .iter()
+            .map(|rule| match query::parse(&rule.query) {
+                Ok(expr) => Ok((expr, rule.tags.iter().cloned().collect())),
+                Err(MessageStoreError::InvalidQuery(e)) => Err(MessageStoreError::InvalidQuery(
+                    format!("{} in rule {}", e, rule.query),
+                )),
+                Err(e) => Err(e),
+            })
+            .collect::<Result<Vec<(Expr, HashSet<String>)>, MessageStoreError>>()
+            .map(Rules)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Applies the tag changes of every rule matching `msg`, in order, so that a rule sees the
+    /// tags set by the ones before it. Returns whether the tags of `msg` changed.
+    pub fn apply(&self, msg: &mut Message) -> bool {
+        let before = msg.tags.clone();
+        for (expr, tags) in self.0.iter() {
+            if matcher::matches(expr, msg) {
+                msg.apply_tags(tags);
+            }
+        }
+        before != msg.tags
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Rules;
+    use crate::config::TagRule;
+    use crate::message::address::Address;
+    use crate::message::Message;
+
+    fn rule(query: &str, tags: &[&str]) -> TagRule {
+        TagRule {
+            query: query.to_string(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let rules = Rules::new(&[
+            rule("from:noreply", &["-inbox"]),
+            rule("from:ci-bot@example.com", &["+ci", "+noreply"]),
+            rule("tag:ci", &["-unread"]),
+        ])
+        .unwrap();
+        let mut msg = Message {
+            from: Address::parse("CI <ci-bot@example.com>"),
+            tags: vec!["inbox".to_string(), "unread".to_string()]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert!(rules.apply(&mut msg));
+        let mut tags = msg.tags.iter().cloned().collect::<Vec<String>>();
+        tags.sort();
+        assert_eq!(tags, vec!["ci", "inbox", "noreply"]);
+        assert!(!rules.apply(&mut msg));
+
+        assert!(Rules::new(&[rule("nope:x", &["a"])]).is_err());
+    }
+}
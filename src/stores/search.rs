@@ -1,18 +1,47 @@
 use std::path::PathBuf;
 
+use super::query::Expr;
 use super::MessageStoreError;
 use crate::stores::_impl::tantivy::TantivyStore;
 use crate::message::Message;
 use super::Store;
 use chrono::{DateTime, Utc};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+impl Default for SortOrder {
+    fn default() -> SortOrder {
+        SortOrder::Descending
+    }
+}
+
 pub trait Searcher: Store {
     fn latest(&mut self, num: usize) -> Result<Vec<Message>, MessageStoreError>;
     fn search_fuzzy(&self, query: String, num: usize) -> Result<Vec<Message>, MessageStoreError>;
+    fn query(
+        &self,
+        query: &str,
+        num: usize,
+        order: SortOrder,
+    ) -> Result<Vec<Message>, MessageStoreError>;
+    /// Number of messages matching `query`, however many there are.
+    fn count(&self, query: &str) -> Result<usize, MessageStoreError>;
+    fn search_expr(
+        &self,
+        expr: &Expr,
+        num: usize,
+        order: SortOrder,
+    ) -> Result<Vec<Message>, MessageStoreError>;
     fn search_by_date(
         &self,
-        start: DateTime<Utc>,
-        end: DateTime<Utc>,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+        num: usize,
+        order: SortOrder,
     ) -> Result<Vec<Message>, MessageStoreError>;
     fn start_index(
         &mut self,
@@ -23,8 +52,11 @@ pub trait Searcher: Store {
         &mut self,
     ) -> Result<(), MessageStoreError>;
 
+    /// Drops the changes staged since the last commit.
+    fn abort_index(&mut self) -> Result<(), MessageStoreError>;
+
 }
 
-pub fn default_searcher(path: PathBuf) -> impl Searcher {
+pub fn default_searcher(path: PathBuf) -> Result<impl Searcher, MessageStoreError> {
     TantivyStore::new(path)
 }
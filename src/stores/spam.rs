@@ -0,0 +1,237 @@
+use super::MessageStoreError;
+use crate::config::SpamConfig;
+use crate::message::Message;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use tantivy::tokenizer::{TextAnalyzer, TokenStream, TokenizerManager};
+
+pub const SPAM_TAG: &str = "spam";
+pub const HAM_TAG: &str = "ham";
+
+/// Number of tokens, the furthest from neutral, a message is scored on.
+const INTERESTING_TOKENS: usize = 15;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct TokenCounts {
+    spam: u64,
+    ham: u64,
+}
+
+/// Naive Bayes token counts: in how many spam and ham messages each token was seen.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpamModel {
+    pub spam_messages: u64,
+    pub ham_messages: u64,
+    tokens: HashMap<String, TokenCounts>,
+}
+
+impl SpamModel {
+    pub fn learn(&mut self, tokens: &HashSet<String>, spam: bool) {
+        if spam {
+            self.spam_messages += 1;
+        } else {
+            self.ham_messages += 1;
+        }
+        for token in tokens {
+            let counts = self.tokens.entry(token.clone()).or_default();
+            if spam {
+                counts.spam += 1;
+            } else {
+                counts.ham += 1;
+            }
+        }
+    }
+
+    /// Probability that a message with the token is spam. Rare tokens are pulled towards 0.5
+    /// as Robinson suggests, so that a token seen once does not decide alone.
+    fn token_probability(&self, counts: &TokenCounts) -> f64 {
+        let spam = counts.spam as f64 / self.spam_messages as f64;
+        let ham = counts.ham as f64 / self.ham_messages as f64;
+        let seen = (counts.spam + counts.ham) as f64;
+        (0.5 + seen * spam / (spam + ham)) / (1.0 + seen)
+    }
+
+    /// Probability that a message made of `tokens` is spam, from 0 to 1. `None` until both
+    /// spam and ham messages have been learned.
+    pub fn score(&self, tokens: &HashSet<String>) -> Option<f64> {
+        if self.spam_messages == 0 || self.ham_messages == 0 {
+            return None;
+        }
+        let mut probabilities = tokens
+            .iter()
+            .filter_map(|token| self.tokens.get(token))
+            .map(|counts| self.token_probability(counts))
+            .collect::<Vec<f64>>();
+        probabilities.sort_by(|a, b| {
+            (b - 0.5)
+                .abs()
+                .partial_cmp(&(a - 0.5).abs())
+                .unwrap_or(Ordering::Equal)
+        });
+        probabilities.truncate(INTERESTING_TOKENS);
+        // Combined in log space, the products of many probabilities underflow.
+        let (spam, ham) = probabilities.iter().fold((0.0, 0.0), |(spam, ham), p| {
+            (spam + p.ln(), ham + (1.0 - p).ln())
+        });
+        Some(1.0 / (1.0 + (ham - spam).exp()))
+    }
+}
+
+/// The spam model of a store, saved next to its index.
+pub struct SpamFilter {
+    pub model: SpamModel,
+    path: PathBuf,
+    analyzer: TextAnalyzer,
+    /// Score from which messages are tagged as spam.
+    pub threshold: f64,
+}
+
+impl SpamFilter {
+    /// Loads the model saved at `path`, or starts untrained if there is none.
+    pub fn load(path: PathBuf) -> Result<SpamFilter, MessageStoreError> {
+        let err = |e: String| {
+            MessageStoreError::CouldNotLoadSpamModel(format!("{}: {}", path.display(), e))
+        };
+        let model = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| err(e.to_string()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => SpamModel::default(),
+            Err(e) => return Err(err(e.to_string())),
+        };
+        Ok(SpamFilter {
+            model,
+            path,
+            // The analyzer of the subject and body fields of the index.
+            analyzer: TokenizerManager::default()
+                .get("default")
+                .expect("tantivy always registers the default tokenizer"),
+            threshold: SpamConfig::default().threshold,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), MessageStoreError> {
+        let err = |e: String| {
+            MessageStoreError::CouldNotSaveSpamModel(format!("{}: {}", self.path.display(), e))
+        };
+        let json = serde_json::to_string(&self.model).map_err(|e| err(e.to_string()))?;
+        fs::write(&self.path, json).map_err(|e| err(e.to_string()))
+    }
+
+    /// Words of the subject and the body as the index sees them, subject words marked as such
+    /// since they weigh differently, along with the domain of the sender.
+    pub fn tokens(&self, msg: &Message) -> HashSet<String> {
+        let mut tokens = HashSet::new();
+        self.analyzer
+            .token_stream(&msg.subject)
+            .process(&mut |token| {
+                tokens.insert(format!("subject:{}", token.text));
+            });
+        if !msg.body.is_empty() {
+            self.analyzer
+                .token_stream(&msg.get_body(None).as_text())
+                .process(&mut |token| {
+                    tokens.insert(token.text.clone());
+                });
+        }
+        if let Some((_, domain)) = msg.from.email.rsplit_once('@') {
+            tokens.insert(format!("from:{}", domain.to_lowercase()));
+        }
+        tokens
+    }
+
+    pub fn score(&self, msg: &Message) -> Option<f64> {
+        self.model.score(&self.tokens(msg))
+    }
+
+    /// Scores a message about to be indexed, tagging it as spam above the threshold. Such
+    /// messages are marked `auto_spam` so that the filter does not learn its own verdicts.
+    pub fn classify(&self, msg: &mut Message) {
+        msg.spam_score = self.score(msg);
+        if msg
+            .spam_score
+            .map_or(false, |score| score >= self.threshold)
+            && msg.tags.insert(SPAM_TAG.to_string())
+        {
+            msg.auto_spam = true;
+        }
+    }
+}
+
+/// Takes the spam tag of `msg` back from the filter when the tag `changes` of the user say
+/// whether it is spam or ham, so that it is learned from again. Returns whether it did.
+pub fn confirm_tags(msg: &mut Message, changes: &HashSet<String>) -> bool {
+    let verdict = changes
+        .iter()
+        .map(|change| change.trim_start_matches(|c| c == '+' || c == '-'))
+        .any(|tag| tag == SPAM_TAG || tag == HAM_TAG);
+    let confirmed = msg.auto_spam && verdict;
+    if confirmed {
+        msg.auto_spam = false;
+    }
+    confirmed
+}
+
+#[cfg(test)]
+mod test {
+    use super::{confirm_tags, SpamFilter, SpamModel, SPAM_TAG};
+    use crate::message::Message;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    fn tokens(text: &str) -> HashSet<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn scores_messages_after_learning() {
+        let mut model = SpamModel::default();
+        assert_eq!(model.score(&tokens("cheap pills")), None);
+        for _ in 0..5 {
+            model.learn(&tokens("cheap pills winner click now"), true);
+            model.learn(&tokens("meeting notes for the release"), false);
+        }
+        model.learn(&tokens("release winner announced"), false);
+        assert!(model.score(&tokens("click now for cheap pills")).unwrap() > 0.99);
+        assert!(
+            model
+                .score(&tokens("notes of the release meeting"))
+                .unwrap()
+                < 0.01
+        );
+        assert_eq!(model.score(&tokens("unknown words only")), Some(0.5));
+    }
+    #[test]
+    fn marks_the_spam_it_tags_until_the_user_confirms() {
+        let mut filter = SpamFilter::load(PathBuf::from("/nonexistent/spam.json")).unwrap();
+        for _ in 0..5 {
+            filter
+                .model
+                .learn(&tokens("subject:cheap subject:pills"), true);
+            filter
+                .model
+                .learn(&tokens("subject:meeting subject:notes"), false);
+        }
+        let mut msg = Message {
+            subject: "Cheap pills".to_string(),
+            ..Default::default()
+        };
+        filter.classify(&mut msg);
+        assert!(msg.spam_score.unwrap() > filter.threshold);
+        assert!(msg.tags.contains(SPAM_TAG));
+        assert!(msg.auto_spam);
+        let changes = vec!["+spam".to_string()].into_iter().collect();
+        assert!(confirm_tags(&mut msg, &changes));
+        assert!(!msg.auto_spam);
+    }
+
+    #[test]
+    fn fails_on_a_model_it_cannot_read() {
+        let dir = TempDir::new("rms-spam").unwrap();
+        assert!(SpamFilter::load(dir.path().to_path_buf()).is_err());
+        assert!(SpamFilter::load(dir.path().join("spam.json")).is_ok());
+    }
+}